@@ -104,6 +104,13 @@ fn greeting_view(_config: &Config, request: &Request, response: Response) -> Res
 	Response {template: ~"hello.html", ..response}
 }
 
+fn shutdown_view(shutdown: oldcomm::Chan<()>, response: Response) -> Response
+{
+	info!("received shutdown request");
+	oldcomm::send(shutdown, ());
+	Response {body: server::StringBody(@~"<!DOCTYPE html>\n<meta charset=utf-8>\n<title>Shutdown</title>\n<p>The server is shutting down.</p>"), template: ~"", ..response}
+}
+
 enum StateMesg
 {
 	AddListener(~str, oldcomm::Chan<int>),	// str is used to identify the listener
@@ -178,7 +185,8 @@ fn uptime_sse(registrar: StateChan, request: &Request, push: server::PushChan) -
 {
 	let seconds = request.params.get(&~"units") == ~"s";
 	
-	do spawn_moded_listener(task::ThreadPerCore) |control_port: server::ControlPort|
+	// spawn_sse finishes the event stream once we return.
+	do server::spawn_sse(push) |control_port: server::ControlPort|
 	{
 		info!("starting uptime sse stream");
 		let notify_port = oldcomm::Port();
@@ -235,7 +243,12 @@ fn main()
 	
 	let greeting_v: ResponseHandler = greeting_view;
 	let home_v: ResponseHandler = |config, request, response, copy options| {home_view(config, &options, request, response)};
-	let shutdown_v: ResponseHandler = |_config, _request, _response| {info!("received shutdown request"); libc::exit(0)};
+	
+	// The shutdown view can't call server.shutdown itself (the server waits for the
+	// view's connection to close) so it signals main which does the shutdown.
+	let shutdown_port = oldcomm::Port();
+	let shutdown_chan = oldcomm::Chan(&shutdown_port);
+	let shutdown_v: ResponseHandler = |_config, _request, response| {shutdown_view(shutdown_chan, response)};
 	
 	let config = server::Config
 	{
@@ -255,10 +268,14 @@ fn main()
 		]),
 		sse: linear_map_from_vector(~[(~"/uptime", up)]),
 		settings: linear_map_from_vector(~[(~"debug",  ~"true")]),
+		autoindex: ~"autoindex.html",
 		..server::initialize_config()
 	};
 	
-	server::start(&config);
-	info!("exiting sample server");		// usually don't land here
+	let handle = server::start_server(&config);
+	oldcomm::recv(shutdown_port);
+	handle.shutdown();
+	oldcomm::send(registrar, Shutdown);
+	info!("exiting sample server");
 }
 
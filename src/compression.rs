@@ -0,0 +1,104 @@
+//! Content codings used to compress response bodies (see section 3.5 of RFC 2616).
+// std::flate produces raw deflate data so we add the gzip (RFC 1952) and zlib
+// (RFC 1950) wrappers ourselves.
+
+/// Returns bytes compressed using the gzip format.
+pub fn gzip(bytes: &[u8]) -> ~[u8]
+{
+	let deflated = std::flate::deflate_bytes(bytes);
+
+	let mut result = ~[];
+	vec::reserve(&mut result, deflated.len() + 18u);
+	vec::push_all(&mut result, [0x1Fu8, 0x8Bu8, 8u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0xFFu8]);	// magic, deflate, no flags, no mtime, unknown OS
+	vec::push_all(&mut result, deflated);
+	push_u32_le(&mut result, crc32(bytes));
+	push_u32_le(&mut result, bytes.len() as u32);
+	result
+}
+
+/// Returns bytes compressed using the zlib format (which is what HTTP calls deflate).
+pub fn deflate(bytes: &[u8]) -> ~[u8]
+{
+	let deflated = std::flate::deflate_bytes(bytes);
+
+	let mut result = ~[];
+	vec::reserve(&mut result, deflated.len() + 6u);
+	vec::push_all(&mut result, [0x78u8, 0x9Cu8]);			// deflate with a 32K window, default compression
+	vec::push_all(&mut result, deflated);
+
+	let checksum = adler32(bytes);
+	vec::push_all(&mut result, [(checksum >> 24) as u8, (checksum >> 16) as u8, (checksum >> 8) as u8, checksum as u8]);
+	result
+}
+
+/// Returns the entity tag for a body which has been coded using coding, e.g. "\"64-2ec86d6f-gzip\""
+/// for a gzipped body whose tag was "\"64-2ec86d6f\"". Section 13.3.3 says that each
+/// representation needs its own strong validator.
+pub fn coded_etag(etag: &str, coding: &str) -> ~str
+{
+	fmt!("%s-%s\"", etag.slice(0, etag.len() - 1u), coding)
+}
+
+priv fn push_u32_le(bytes: &mut ~[u8], value: u32)
+{
+	vec::push_all(bytes, [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]);
+}
+
+// The CRC used by gzip, see section 8 of RFC 1952.
+priv fn crc32(bytes: &[u8]) -> u32
+{
+	let mut crc = 0xFFFFFFFFu32;
+	for bytes.each |b|
+	{
+		crc ^= *b as u32;
+		for 8.times
+		{
+			crc = if crc & 1u32 != 0u32 {(crc >> 1) ^ 0xEDB88320u32} else {crc >> 1};
+		}
+	}
+	crc ^ 0xFFFFFFFFu32
+}
+
+// The checksum used by zlib, see section 9 of RFC 1950.
+priv fn adler32(bytes: &[u8]) -> u32
+{
+	let mut a = 1u32;
+	let mut b = 0u32;
+	for bytes.each |byte|
+	{
+		a = (a + *byte as u32) % 65521u32;
+		b = (b + a) % 65521u32;
+	}
+	(b << 16) | a
+}
+
+// ---- Unit Tests ------------------------------------------------------------
+#[test]
+fn checksums()
+{
+	assert crc32(str::to_bytes("123456789")) == 0xCBF43926u32;
+	assert adler32(str::to_bytes("Wikipedia")) == 0x11E60398u32;
+}
+
+#[test]
+fn compressed_formats()
+{
+	let text = str::to_bytes(str::repeat("hello world ", 100u));
+
+	let gzipped = gzip(text);
+	assert gzipped.len() < text.len();
+	assert gzipped[0] == 0x1Fu8 && gzipped[1] == 0x8Bu8;
+	assert std::flate::inflate_bytes(vec::view(gzipped, 10u, gzipped.len() - 8u)) == text;
+	assert vec::slice(gzipped, gzipped.len() - 4u, gzipped.len()) == ~[0xB0u8, 0x04u8, 0u8, 0u8];	// 1200 bytes
+
+	let deflated = deflate(text);
+	assert deflated[0] == 0x78u8 && deflated[1] == 0x9Cu8;
+	assert std::flate::inflate_bytes(vec::view(deflated, 2u, deflated.len() - 4u)) == text;
+}
+
+#[test]
+fn coded_etags()
+{
+	assert coded_etag("\"64-2ec86d6f\"", "gzip") == ~"\"64-2ec86d6f-gzip\"";
+	assert coded_etag("W/\"abc\"", "deflate") == ~"W/\"abc-deflate\"";
+}
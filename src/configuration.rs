@@ -11,18 +11,41 @@ use std::base64::*;
 /// * port is the TCP port that the server listens on.
 /// * server_info is included in the HTTP response and should include the server name and version.
 /// * resources_root should be a path to where the files associated with URLs are loaded from.
+/// * mounts: directories which the files associated with URLs starting with a prefix are loaded from, e.g. "/static" and "assets".
 /// * routes: maps HTTP request information to a route name.
 /// * views: maps route names to view handler functions.
-/// * static_handler: used to handle URIs that don't match routes, but are found beneath resources_root.
+/// * static_handler: used to handle URIs that don't match routes, but are found beneath resources_root or a mount.
 /// * is_template: returns true if the path is to a mustache template.
 /// * sse: maps EventSource path to a function that creates a task to push server-sent events.
-/// * missing: used to handle URIs that don't match routes, and are not found beneath resources_root.
+/// * missing: used to handle URIs that don't match routes, and are not found beneath resources_root or a mount.
 /// * static_types: maps file extensions (including the period) to mime types.
 /// * read_error: html used when a file fails to load. Must include {{request-path}} template.
 /// * load_rsrc: maps a path rooted at resources_root to a resource body.
+/// * read_rsrc: opens a path rooted at resources_root for reading. Used instead of load_rsrc for files at least stream_size bytes long.
 /// * valid_rsrc: returns true if a path rooted at resources_root points to a file.
+/// * rsrc_info: returns the size and modification time of a path rooted at resources_root.
+/// * list_rsrc: returns the names of the files within a directory rooted at resources_root.
+/// * stream_size: static files at least this large are streamed using read_rsrc instead of being loaded with load_rsrc.
 /// * settings: arbitrary key/value pairs passed into view handlers. If debug is "true" rwebserve debugging 
 /// code will be enabled (among other things this will default the Cache-Control header to "no-cache").
+/// * header_timeout: milliseconds a client has to send the request line and headers once it starts a request.
+/// * body_timeout: milliseconds the server will wait for the next part of a request body.
+/// * keep_alive_timeout: milliseconds an idle connection is kept open waiting for a new request.
+/// * write_timeout: milliseconds the server will wait for a client to accept more response data.
+/// * max_header_bytes: maximum size of the request line and headers, larger requests get a 431 response.
+/// * max_header_count: maximum number of request headers, requests with more get a 431 response.
+/// * max_url_length: maximum length of the request URL, longer URLs get a 414 response.
+/// * max_body_length: maximum size of a request body, larger bodies get a 413 response.
+/// * expect_continue: called for requests with an "Expect: 100-continue" header. Returns false to reject the body with a 417 response.
+/// * languages: the languages templates are written in. The first is the language of templates like "home.html",
+/// the others use templates like "home.fr.html". If there is more than one language the Accept-Language header is used to pick a template.
+/// * compress_types: mime types of response bodies which may be gzip or deflate compressed, e.g. "text/html" or "text/*".
+/// Types which are already compressed (e.g. "image/png") are never compressed.
+/// * index_files: html files served for URLs which map to a directory beneath resources_root, e.g. "index.html".
+/// * autoindex: template used to list the files within directories which have no index file, e.g. "autoindex.html".
+/// The context includes an entries list with name, url, size, and modified fields for each file.
+/// 
+/// A timeout or limit of zero disables the timeout or limit.
 /// 
 /// initialize_config can be used to initialize some of these fields. Note that this is sendable and copyable type.
 pub struct Config
@@ -31,6 +54,7 @@ pub struct Config
 	pub port: u16,
 	pub server_info: ~str,
 	pub resources_root: Path,
+	pub mounts: ~[Mount],
 	pub routes: ~[(Route)],
 	pub views: LinearMap<~str, ResponseHandler>,
 	pub static_handler: ResponseHandler,
@@ -40,8 +64,25 @@ pub struct Config
 	pub static_types: LinearMap<~str, ~str>,
 	pub read_error: ~str,
 	pub load_rsrc: RsrcLoader,
+	pub read_rsrc: RsrcReader,
 	pub valid_rsrc: RsrcExists,
+	pub rsrc_info: RsrcInfo,
+	pub list_rsrc: RsrcLister,
+	pub stream_size: uint,
 	pub settings: LinearMap<~str, ~str>,
+	pub header_timeout: uint,
+	pub body_timeout: uint,
+	pub keep_alive_timeout: uint,
+	pub write_timeout: uint,
+	pub max_header_bytes: uint,
+	pub max_header_count: uint,
+	pub max_url_length: uint,
+	pub max_body_length: uint,
+	pub expect_continue: ContinueHandler,
+	pub languages: ~[~str],
+	pub compress_types: ~[~str],
+	pub index_files: ~[~str],
+	pub autoindex: ~str,
 }
 
 /// Information about incoming http requests. Passed into view functions.
@@ -53,8 +94,9 @@ pub struct Config
 /// * path: path component of the URL. Note that this does not include the query string.
 /// * matches: contains entries from request_path matching a routes URI template.
 /// * params: contains entries from the query portion of the URL. Note that the keys may be duplicated.
-/// * headers: headers from the http request. Note that the names are lower cased.
-/// * body: body of the http request.
+/// * headers: headers from the http request. Note that lookups ignore the case of names.
+/// * body: body of the http request. Note that this may be binary data (use body_text for text bodies).
+/// * negotiated_type: the mime type chosen for the response using the Accept header, e.g. "application/json".
 pub struct Request
 {
 	pub version: ~str,
@@ -64,26 +106,43 @@ pub struct Request
 	pub path: ~str,
 	pub matches: LinearMap<~str, ~str>,
 	pub params: LinearMap<~str, ~str>,
-	pub headers: LinearMap<~str, ~str>,
-	pub body: ~str,
+	pub headers: Headers,
+	pub body: ~[u8],
+	pub negotiated_type: ~str,
 	
 	drop {}			// TODO: enable this (was getting a compiler assert earlier)
 }
 
+impl Request
+{
+	/// Returns the body as a string or None if the body is not utf-8.
+	fn body_text(&self) -> option::Option<~str>
+	{
+		if str::is_utf8(self.body)
+		{
+			option::Some(str::from_bytes(self.body))
+		}
+		else
+		{
+			option::None
+		}
+	}
+}
+
 /// Returned by view functions and used to generate http response messages.
 /// 
-/// * status: the status code and message for the response, defaults to "200 OK".
-/// * headers: the HTTP headers to be included in the response.
+/// * status: the status code and reason phrase for the response, defaults to status::OK.
+/// * headers: the HTTP headers to be included in the response. Use add for headers like Set-Cookie which may be repeated.
 /// * body: contents the section after headers.
-/// * template: path relative to resources_root containing a template file.
+/// * template: path relative to resources_root containing a template file (or a URL path starting with a mount's prefix).
 /// * context: hashmap used when rendering the template file.
 /// 
 /// If template is not empty then body should be empty. If body is not empty then
 /// headers["Content-Type"] should usually be explicitly set.
 pub struct Response
 {
-	pub status: ~str,
-	pub headers: LinearMap<~str, ~str>,
+	pub status: Status,
+	pub headers: Headers,
 	pub body: Body,
 	pub template: ~str,									// an URL path is very similar to a path::PosixPath, but that is conditionally compiled in
 	pub context: HashMap<@~str, mustache::Data>,	//  mustache wants HashMaps
@@ -96,11 +155,50 @@ pub struct Response
 /// The type of an HTTP body is determined by the content-type header. If it is a text mime type
 /// then the body with be some flavor of text. However for types like image/png the body will
 /// be binary data. This type allows us to avoid copying a text reply to a byte buffer.
+///
+/// Views which generate a lot of data (e.g. a large CSV export) can return a ProducerBody.
+/// Its chunks are sent as they are produced using the chunked transfer-coding (HTTP/1.0
+/// clients get the chunks as is and the connection is closed once the body is complete).
+/// A TrailedBody is also chunked so that the trailers can be sent after the last chunk.
 pub enum Body
 {
 	StringBody(@~str),
 	BinaryBody(@~[u8]),
 	CompoundBody(@[@Body]),		// concatenation of strings and vectors blows if they are large
+	ReaderBody(io::Reader, uint, uint),	// offset and length of part of a resource, sent in chunks so large files aren't loaded into memory
+	ProducerBody(BodyProducer),
+	TrailedBody(@Body, @~[~str], TrailerProducer),	// body, names of the trailers, and the trailers (which are dropped for HTTP/1.0 clients)
+}
+
+/// Returns the next chunk of a ProducerBody or None once the body is complete. If an
+/// error is returned the connection is closed without completing the body.
+pub type BodyProducer = fn@ () -> result::Result<option::Option<~[u8]>, ~str>;
+
+/// Returns the trailers sent after a TrailedBody (e.g. a Content-MD5 computed as the body was produced).
+pub type TrailerProducer = fn@ () -> Headers;
+
+/// Returns a ProducerBody whose chunks are received from port (typically from a task spawned
+/// by a view). The body is complete once an empty chunk is received. If no chunk arrives
+/// within timeout milliseconds (e.g. because the task failed) the body is abandoned. Zero
+/// disables the timeout, config.write_timeout is often a good choice.
+pub fn channel_body(port: oldcomm::Port<~[u8]>, timeout: uint) -> Body
+{
+	ProducerBody(|move port|
+	{
+		let chunk = if timeout > 0u
+		{
+			std::timer::recv_timeout(std::uv::global_loop::get(), timeout, port)
+		}
+		else
+		{
+			option::Some(oldcomm::recv(port))
+		};
+		match chunk
+		{
+			option::Some(move chunk) => result::Ok(if chunk.is_empty() {option::None} else {option::Some(chunk)}),
+			option::None => result::Err(fmt!("no chunk was produced within %? ms", timeout)),
+		}
+	})
 }
 
 pub impl Body : ToStr
@@ -122,20 +220,32 @@ pub impl Body : ToStr
 			{
 				do parts.foldl(~"") |result, part| {result + part.to_str()}
 			}
+			ReaderBody(_reader, offset, len) =>
+			{
+				fmt!("<%? bytes at %?>", len, offset)
+			}
+			ProducerBody(_producer) =>
+			{
+				~"<produced data>"
+			}
+			TrailedBody(body, _names, _trailers) =>
+			{
+				body.to_str()
+			}
 		}
 	}
 }
 
 /// Function used to generate an HTTP response.
 /// 
-/// On entry reponse.status will typically be set to \"200 OK\". response.headers will include something like the following:
+/// On entry reponse.status will typically be set to status::OK. response.headers will include something like the following:
 /// * Server: whizbang server 1.0
 /// * Content-Length: 0 (if non-zero rwebserve will not compute the body length)
 /// * Content-Type:  text/html; charset=UTF-8
 /// Context will be initialized with:
 /// * request-path: the path component of the url within the client request message (e.g. '/home').
 /// * status-code: the code that will be included in the response message (e.g. '200' or '404').
-/// * status-mesg: the code that will be included in the response message (e.g. 'OK' or 'Not Found').
+/// * status-mesg: the reason phrase that will be included in the response message (e.g. 'OK' or 'Not Found').
 /// * request-version: HTTP version of the request message (e.g. '1.1').
 /// 
 /// On exit the response will have:
@@ -148,21 +258,57 @@ pub impl Body : ToStr
 /// After the function returns a base-path entry is added to the response.context with the url to the directory containing the template file.
 pub type ResponseHandler = fn~ (config: &Config, request: &Request, response: Response) -> Response;
 
+/// Returns true if the client should send the body of a request which used "Expect: 100-continue".
+/// 
+/// route is the name of the route matching the request (or empty if no route matched). headers are
+/// the request headers.
+pub type ContinueHandler = fn~ (config: &Config, route: &str, headers: &Headers) -> bool;
+
 /// Returns true if the file at path should be treated as a mustache template.
 pub type IsTemplateFile = fn~ (config: &Config, path: &str) -> bool;
 
 /// Maps a path rooted at resources_root to a resource body.
 pub type RsrcLoader = fn~ (path: &Path) -> result::Result<~[u8], ~str>;
 
+/// Opens a path rooted at resources_root so that it can be read a piece at a time.
+pub type RsrcReader = fn~ (path: &Path) -> result::Result<io::Reader, ~str>;
+
 /// Returns true if a path rooted at resources_root points to a file.
 pub type RsrcExists = fn~ (path: &Path) -> bool;
 
+/// Returns metadata for a path rooted at resources_root (or None if it couldn't be found).
+pub type RsrcInfo = fn~ (path: &Path) -> option::Option<RsrcMetadata>;
+
+/// Returns the names of the files within a directory rooted at resources_root (or None if
+/// path is not a directory).
+pub type RsrcLister = fn~ (path: &Path) -> option::Option<~[~str]>;
+
+/// * size: the size of the resource in bytes.
+/// * modified: the time the resource was last changed in seconds since 1970 (UTC).
+pub struct RsrcMetadata
+{
+	pub size: uint,
+	pub modified: i64,
+}
+
+/// * mime_type: the type the route's view produces, e.g. "text/html".
+/// * other_types: additional types the view can produce (see NegotiatedRoute).
 pub struct Route
 {
 	pub route: ~str,
 	pub method: ~str,
 	pub template: ~[uri_template::Component],
 	pub mime_type: ~str,
+	pub other_types: ~[~str],
+}
+
+impl Route
+{
+	/// Returns mime_type followed by other_types.
+	fn mime_types(&self) -> ~[~str]
+	{
+		~[copy self.mime_type] + self.other_types
+	}
 }
 
 /// route is an arbitrary name.
@@ -174,21 +320,74 @@ pub fn Route(route: ~str, method: ~str, template: ~str) -> Route
 }
 
 /// Like the route function except that mime_type does not default to "text/html".
+///
+/// Several routes may use the same template with different mime types. The route
+/// whose type the client's Accept header gives the highest quality value is used.
 pub fn TypedRoute(route: ~str, method: ~str, template: ~str, mime_type: ~str) -> Route
 {
-	Route {route: route, method: method, template: uri_template::compile(template), mime_type: mime_type}
+	NegotiatedRoute(route, method, template, ~[mime_type])
+}
+
+/// Like TypedRoute except that the route's view can produce any of the mime_types.
+///
+/// The type the client prefers is passed to the view as request.negotiated_type
+/// and responses include a "Vary: Accept" header.
+pub fn NegotiatedRoute(route: ~str, method: ~str, template: ~str, mime_types: ~[~str]) -> Route
+{
+	assert vec::is_not_empty(mime_types);
+	Route {route: route, method: method, template: uri_template::compile(template), mime_type: copy mime_types[0], other_types: vec::tail(mime_types)}
+}
+
+/// Maps URLs starting with prefix to files beneath root.
+/// 
+/// * prefix: the start of the URLs served from root, e.g. "/static" (which matches "/static/app.css" but not "/statics").
+/// * root: the directory that the mount's files are loaded from.
+/// * static_types: maps file extensions (including the period) to mime types.
+/// * is_template: returns true if the path is to a mustache template.
+/// * cache_control: if not empty this is used as the Cache-Control header for the mount's files, e.g. "max-age=86400".
+/// 
+/// If several mounts match a URL the mount with the longest prefix is used.
+pub struct Mount
+{
+	pub prefix: ~str,
+	pub root: Path,
+	pub static_types: LinearMap<~str, ~str>,
+	pub is_template: IsTemplateFile,
+	pub cache_control: ~str,
+}
+
+/// Returns a mount which uses the default static_types and is_template and has no caching policy.
+pub fn Mount(prefix: ~str, root: Path) -> Mount
+{
+	assert prefix.starts_with("/");
+	Mount {prefix: prefix, root: root, static_types: default_static_types(), is_template: is_text_file, cache_control: ~""}
 }
 
 /// Initalizes several config fields.
 /// 
 /// * port is initialized to 80.
+/// * mounts is initialized to an empty list (i.e. all files are beneath resources_root).
 /// * static_handler is initialized to a reasonable view handler.
 /// * is_template: is initialized to a function that returns true if the file has an extension of text/plain mime type.
 /// * missing is initialized to a view that assume a \"not-found.html\" is at the root.
 /// * static_types is given entries for audio, image, video, and text extensions.
 /// * read_error is initialized to a reasonable English language html error message.
 /// * load_rsrc: is initialized to io::read_whole_file_str.
+/// * read_rsrc: is initialized to io::file_reader.
 /// * valid_rsrc: is initialized to os::path_exists && !os::path_is_dir.
+/// * rsrc_info: is initialized to a function which uses stat.
+/// * list_rsrc: is initialized to a function which uses os::list_dir.
+/// * stream_size is initialized to 1M.
+/// * header_timeout and body_timeout are initialized to 30s.
+/// * keep_alive_timeout is initialized to 15s.
+/// * write_timeout is initialized to 60s.
+/// * max_header_bytes is initialized to 16K, max_header_count to 100, and max_url_length to 8K.
+/// * max_body_length is initialized to 16M.
+/// * expect_continue is initialized to a function which accepts every request.
+/// * languages is initialized to an empty list (i.e. templates are not localized).
+/// * compress_types is initialized to an empty list (i.e. responses are not compressed).
+/// * index_files is initialized to "index.html".
+/// * autoindex is initialized to an empty string (i.e. directories are not listed).
 pub fn initialize_config() -> Config
 {
 	Config 
@@ -197,39 +396,14 @@ pub fn initialize_config() -> Config
 		port: 80_u16,
 		server_info: ~"",
 		resources_root: GenericPath::from_str(~""),
+		mounts: ~[],
 		routes: ~[],
 		views: LinearMap(),
 		static_handler: static_view,
 		is_template: is_text_file,
 		sse: LinearMap(),
 		missing: missing_view,
-		static_types: utils::linear_map_from_vector(~[
-			(~".m4a", ~"audio/mp4"),
-			(~".m4b", ~"audio/mp4"),
-			(~".mp3", ~"audio/mpeg"),
-			(~".wav", ~"audio/vnd.wave"),
-			
-			(~".gif", ~"image/gif"),
-			(~".jpeg", ~"image/jpeg"),
-			(~".jpg", ~"image/jpeg"),
-			(~".png", ~"image/png"),
-			(~".tiff", ~"image/tiff"),
-			
-			(~".css", ~"text/css"),
-			(~".csv", ~"text/csv"),
-			(~".html", ~"text/html"),
-			(~".htm", ~"text/html"),
-			(~".txt", ~"text/plain"),
-			(~".text", ~"text/plain"),
-			(~".xml", ~"text/xml"),
-			
-			(~".js", ~"text/javascript"),
-			
-			(~".mp4", ~"video/mp4"),
-			(~".mov", ~"video/quicktime"),
-			(~".mpg", ~"video/mpeg"),
-			(~".mpeg", ~"video/mpeg"),
-			(~".qt", ~"video/quicktime")]),
+		static_types: default_static_types(),
 		read_error: ~"<!DOCTYPE html>
 	<meta charset=utf-8>
 	
@@ -237,8 +411,138 @@ pub fn initialize_config() -> Config
 	
 	<p>Could not read URL {{request-path}}.</p>",
 		load_rsrc: io::read_whole_file,
+		read_rsrc: io::file_reader,
 		valid_rsrc: is_valid_rsrc,
+		rsrc_info: get_rsrc_info,
+		list_rsrc: list_rsrc_dir,
+		stream_size: 1024u*1024u,
 		settings: LinearMap(),
+		header_timeout: 30_000u,
+		body_timeout: 30_000u,
+		keep_alive_timeout: 15_000u,
+		write_timeout: 60_000u,
+		max_header_bytes: 16u*1024u,
+		max_header_count: 100u,
+		max_url_length: 8u*1024u,
+		max_body_length: 16u*1024u*1024u,
+		expect_continue: accept_continue,
+		languages: ~[],
+		compress_types: ~[],
+		index_files: ~[~"index.html"],
+		autoindex: ~"",
+	}
+}
+
+/// Returns the mime types used by initialize_config and Mount.
+pub fn default_static_types() -> LinearMap<~str, ~str>
+{
+	utils::linear_map_from_vector(~[
+		(~".m4a", ~"audio/mp4"),
+		(~".m4b", ~"audio/mp4"),
+		(~".mp3", ~"audio/mpeg"),
+		(~".wav", ~"audio/vnd.wave"),
+		
+		(~".gif", ~"image/gif"),
+		(~".jpeg", ~"image/jpeg"),
+		(~".jpg", ~"image/jpeg"),
+		(~".png", ~"image/png"),
+		(~".tiff", ~"image/tiff"),
+		
+		(~".css", ~"text/css"),
+		(~".csv", ~"text/csv"),
+		(~".html", ~"text/html"),
+		(~".htm", ~"text/html"),
+		(~".txt", ~"text/plain"),
+		(~".text", ~"text/plain"),
+		(~".xml", ~"text/xml"),
+		
+		(~".js", ~"text/javascript"),
+		
+		(~".mp4", ~"video/mp4"),
+		(~".mov", ~"video/quicktime"),
+		(~".mpg", ~"video/mpeg"),
+		(~".mpeg", ~"video/mpeg"),
+		(~".qt", ~"video/quicktime")])
+}
+
+/// Returns the path to the file (or directory) that a URL path maps to. URLs starting with
+/// a mount's prefix map to paths beneath the mount's root and other URLs map to paths
+/// beneath resources_root. Returns None if the path is not beneath its root (e.g. because
+/// the URL has too many .. components).
+pub fn url_to_rsrc(config: &Config, url: &str) -> option::Option<Path>
+{
+	let (root, url) = match find_mount(config, url)
+	{
+		option::Some(i) => (copy config.mounts[i].root, url.slice(config.mounts[i].prefix.len(), url.len())),
+		option::None => (copy config.resources_root, url.to_owned()),
+	};
+	
+	let path = utils::url_to_path(&root, url).normalize();
+	if is_beneath(&root.normalize(), &path)
+	{
+		option::Some(path)
+	}
+	else
+	{
+		option::None
+	}
+}
+
+// Compares components so that "assets-private/key" is not considered to be beneath "assets".
+priv fn is_beneath(root: &Path, path: &Path) -> bool
+{
+	let count = root.components.len();
+	path.is_absolute == root.is_absolute && path.components.len() >= count && vec::view(path.components, 0u, count) == vec::view(root.components, 0u, count)
+}
+
+/// Returns the mime type of the file a URL path maps to using the static_types of the
+/// URL's mount (or config.static_types if the URL doesn't match a mount).
+pub fn rsrc_type(config: &Config, url: &str) -> option::Option<~str>
+{
+	match str::rfind_char(url, '.')
+	{
+		option::Some(index) if !str::contains(url.slice(index, url.len()), "/") =>
+		{
+			let ext = url.slice(index, url.len());
+			match find_mount(config, url)
+			{
+				option::Some(i) => config.mounts[i].static_types.find(&ext),
+				option::None => config.static_types.find(&ext),
+			}
+		}
+		_ =>
+		{
+			option::None
+		}
+	}
+}
+
+// Returns the index of the mount with the longest prefix matching url.
+priv fn find_mount(config: &Config, url: &str) -> option::Option<uint>
+{
+	let mut result = option::None;
+	let mut longest = 0u;
+	for config.mounts.eachi |i, mount|
+	{
+		let len = mount.prefix.len();
+		if str::starts_with(url, mount.prefix) && (url.len() == len || mount.prefix.ends_with("/") || url.char_at(len) == '/')
+		{
+			if result.is_none() || len > longest
+			{
+				result = option::Some(i);
+				longest = len;
+			}
+		}
+	}
+	result
+}
+
+priv fn is_template_rsrc(config: &Config, url: &str) -> bool
+{
+	match find_mount(config, url)
+	{
+		option::Some(i) => (config.mounts[i].is_template)(config, url),
+		option::None => (config.is_template)(config, url),
 	}
 }
 
@@ -247,6 +551,42 @@ pub fn is_valid_rsrc(path: &Path) -> bool
 	os::path_exists(path) && !os::path_is_dir(path)
 }
 
+pub fn get_rsrc_info(path: &Path) -> option::Option<RsrcMetadata>
+{
+	do posix::file_info(path).map |info|
+	{
+		let (size, modified) = *info;
+		RsrcMetadata {size: size, modified: modified}
+	}
+}
+
+pub fn list_rsrc_dir(path: &Path) -> option::Option<~[~str]>
+{
+	if os::path_is_dir(path)
+	{
+		option::Some(os::list_dir(path))
+	}
+	else
+	{
+		option::None
+	}
+}
+
+/// Returns the first of config.index_files found within the directory at path.
+pub fn find_index_file(config: &Config, path: &Path) -> option::Option<~str>
+{
+	do config.index_files.find |name|
+	{
+		(config.valid_rsrc)(&path.push(*name))
+	}
+}
+
+// Default config.expect_continue handler.
+pub fn accept_continue(_config: &Config, _route: &str, _headers: &Headers) -> bool
+{
+	true
+}
+
 // Default config.missing handler. Assumes that there is a "not-found.html"
 // file at the resource root.
 pub fn missing_view(_config: &Config, _request: &Request, response: Response) -> Response
@@ -261,46 +601,261 @@ pub fn missing_view(_config: &Config, _request: &Request, response: Response) ->
 // 1) It's expected that expanding a non-template file is not going to be a performance problem.
 // 2) Using files like *.html.mustache screws up syntax highlighting in editors.
 // 3) Users can install a new is_template closure to do something different.
-pub fn static_view(config: &Config, _request: &Request, response: Response) -> Response
+//
+// If there is a gzipped version of the file (e.g. "foo.js.gz" next to "foo.js") it is used
+// instead for clients which accept gzip. These files are never treated as templates.
+//
+// URLs which map to directories are served using their index file or a listing of
+// the files within them.
+//
+// Files beneath a mount use the mount's static_types, is_template, and cache_control.
+pub fn static_view(config: &Config, request: &Request, response: Response) -> Response
 {
 	let path = mustache::compile_str("{{request-path}}").render_data(mustache::Map(response.context));
 	//let path = mustache::render_str("{{request-path}}", response.context);
-	if (config.is_template)(config, path)
+	let file = match url_to_rsrc(config, path)
+	{
+		option::Some(move file) => file,
+		option::None => return Response {template: ~"not-found.html", ..response},
+	};
+	
+	let mut response = response;
+	match find_mount(config, path)
+	{
+		option::Some(i) if config.mounts[i].cache_control.is_not_empty() =>
+		{
+			response.headers.set_cache_control(copy config.mounts[i].cache_control);
+		}
+		_ =>
+		{
+		}
+	}
+	
+	match (config.list_rsrc)(&file)
+	{
+		option::Some(move names) =>
+		{
+			return directory_view(config, request, response, path, &file, names);
+		}
+		option::None =>
+		{
+		}
+	}
+	
+	let gz_path = url_to_rsrc(config, path + ~".gz");
+	let has_gz = gz_path.is_some() && (config.valid_rsrc)(gz_path.get_ref());
+	if has_gz
+	{
+		response.headers.add_vary("Accept-Encoding");
+	}
+	
+	if has_gz && accepts_gzip(request)
+	{
+		response.headers.insert(~"Content-Encoding", ~"gzip");
+		load_static_file(config, request, response, gz_path.get_ref())
+	}
+	else if is_template_rsrc(config, path)
 	{
 		Response {body: StringBody(@~""), template: path, context: std::map::HashMap(), ..response}
 	}
 	else
 	{
-		let path = utils::url_to_path(&config.resources_root, path);
-		let contents = (config.load_rsrc)(&path);
-		if contents.is_ok()
+		load_static_file(config, request, response, &file)
+	}
+}
+
+priv fn directory_view(config: &Config, request: &Request, response: Response, path: ~str, dir: &Path, names: ~[~str]) -> Response
+{
+	let mut response = response;
+	if !path.ends_with("/")
+	{
+		// Otherwise relative URLs within the directory's page would be resolved using the parent directory.
+		let location = utils::encode_url_path(path) + ~"/" + encode_query(&request.params);
+		info!("redirecting %s to %s", path, location);
+		response.headers.insert(~"Location", copy location);
+		let body = fmt!("<!DOCTYPE html>\n<meta charset=utf-8>\n\n<title>Moved Permanently</title>\n\n<p>Moved to <a href=\"%s\">%s</a>.</p>", location, location);
+		return Response {status: status::MovedPermanently, body: StringBody(@body), template: ~"", context: std::map::HashMap(), ..response};
+	}
+	
+	match find_index_file(config, dir)
+	{
+		option::Some(ref name) =>
 		{
-			Response {body: BinaryBody(@result::unwrap(contents)), template: ~"", context: std::map::HashMap(), ..response}
+			response.context.insert(@~"request-path", mustache::Str(@(path + *name)));
+			static_view(config, request, response)
 		}
-		else
+		option::None if config.autoindex.is_not_empty() =>
+		{
+			pure fn le(a: &~str, b: &~str) -> bool {*a <= *b}
+			
+			let mut entries = ~[];
+			for std::sort::merge_sort(names, le).each |name|
+			{
+				// Hidden files are often things like .htaccess which shouldn't be public.
+				if !name.starts_with(".")
+				{
+					let file = dir.push(*name);
+					let is_file = (config.valid_rsrc)(&file);
+					if is_file || (config.list_rsrc)(&file).is_some()
+					{
+						let entry = std::map::HashMap();
+						entry.insert(@~"name", mustache::Str(@copy *name));
+						entry.insert(@~"url", mustache::Str(@(if is_file {utils::encode_url_path(*name)} else {utils::encode_url_path(*name) + ~"/"})));
+						match (config.rsrc_info)(&file)
+						{
+							option::Some(ref info) =>
+							{
+								entry.insert(@~"size", mustache::Str(@(if is_file {info.size.to_str()} else {~"-"})));
+								entry.insert(@~"modified", mustache::Str(@std::time::at_utc(std::time::Timespec {sec: info.modified, nsec: 0i32}).rfc822()));
+							}
+							option::None =>
+							{
+							}
+						}
+						vec::push(&mut entries, mustache::Map(entry));
+					}
+				}
+			}
+			
+			response.context.insert(@~"entries", mustache::Vec(@entries));
+			Response {body: StringBody(@~""), template: copy config.autoindex, ..response}
+		}
+		option::None =>
 		{
-			error!("failed to open %s: %s", path.to_str(), contents.get_err());
 			Response {template: ~"not-found.html", ..response}
 		}
 	}
 }
 
-pub fn is_text_file(config: &Config, path: &str) -> bool
+// Request params have already been decoded so they are encoded again. Note that they
+// are sorted because the original order has been lost.
+priv fn encode_query(params: &LinearMap<~str, ~str>) -> ~str
+{
+	pure fn le(a: &(~str, ~str), b: &(~str, ~str)) -> bool {a.first() <= b.first()}
+	
+	let params = std::sort::merge_sort(utils::vector_from_linear_map(params), le);
+	let params = do params.map |param| {utils::encode_url_path(param.first()) + ~"=" + utils::encode_url_path(param.second())};
+	if params.is_not_empty() {~"?" + str::connect(params, "&")} else {~""}
+}
+
+// Adds Last-Modified and ETag headers (see section 13.3) and returns either the file
+// at path (or the ranges of it the client asked for) or, if the client's cached copy
+// is still valid, 304 Not Modified. Files at least config.stream_size bytes long are
+// returned as a ReaderBody so that they are read as they are sent. Note that the file
+// is opened here so that errors are reported before the response header is sent.
+priv fn load_static_file(config: &Config, request: &Request, response: Response, path: &Path) -> Response
 {
-	match str::rfind_char(path, '.')
+	let mut response = response;
+	match (config.rsrc_info)(path)
 	{
-		option::Some(index) =>
+		option::Some(ref info) =>
 		{
-			let ext = path.slice(index, path.len());
-			match config.static_types.find(&ext)
+			let etag = fmt!("\"%x-%x\"", info.size, info.modified as uint);
+			let modified = std::time::at_utc(std::time::Timespec {sec: info.modified, nsec: 0i32});
+			response.headers.insert(~"Last-Modified", modified.rfc822());
+			response.headers.set_etag(copy etag);
+			
+			if request.method == ~"GET" || request.method == ~"HEAD"
 			{
-				option::Some(mine_type) => mine_type.starts_with(~"text/"),
-				option::None => false,
+				match not_modified_etag(request, etag, info.modified)
+				{
+					option::Some(move tag) =>
+					{
+						response.headers.set_etag(tag);
+						return Response {status: status::NotModified, body: StringBody(@~""), template: ~"", context: std::map::HashMap(), ..response};
+					}
+					option::None =>
+					{
+					}
+				}
+			}
+			
+			if config.stream_size > 0u && info.size >= config.stream_size
+			{
+				debug!("streaming %? bytes from %s", info.size, path.to_str());
+				return match (config.read_rsrc)(path)
+				{
+					result::Ok(reader) =>
+					{
+						ranges::range_response(request, Response {template: ~"", context: std::map::HashMap(), ..response}, info.size, |offset, len| {ReaderBody(reader, offset, len)})
+					}
+					result::Err(ref mesg) =>
+					{
+						static_file_error(response, path, *mesg)
+					}
+				};
 			}
 		}
 		option::None =>
 		{
-			false
 		}
-	} 
+	}
+	
+	let contents = (config.load_rsrc)(path);
+	if contents.is_ok()
+	{
+		let contents = @result::unwrap(contents);
+		ranges::range_response(request, Response {template: ~"", context: std::map::HashMap(), ..response}, contents.len(), |offset, len| {BinaryBody(@vec::slice(*contents, offset, offset + len))})
+	}
+	else
+	{
+		static_file_error(response, path, contents.get_err())
+	}
+}
+
+priv fn static_file_error(response: Response, path: &Path, mesg: &str) -> Response
+{
+	error!("failed to open %s: %s", path.to_str(), mesg);
+	
+	let mut response = response;
+	response.headers.remove("Content-Encoding");
+	response.headers.remove("Last-Modified");
+	response.headers.remove("ETag");
+	Response {status: status::NotFound, template: ~"not-found.html", ..response}
+}
+
+// Section 14.26 says that If-None-Match takes precedence over If-Modified-Since and
+// that GET and HEAD requests may use the weak comparison function. Returns the tag
+// to use with 304 Not Modified or None if the client's copy is stale. Note that the
+// client's copy may have been compressed (and given its own tag) by compress_body in
+// which case it's only still valid if the client still accepts that coding.
+priv fn not_modified_etag(request: &Request, etag: &str, modified: i64) -> option::Option<~str>
+{
+	let tags = request.headers.tokens("If-None-Match");
+	if tags.is_not_empty()
+	{
+		let ranges = negotiation::parse_qvalues(request.headers.find("Accept-Encoding").get_default(~""));
+		let codings = do vec::filter(~[~"gzip", ~"deflate"]) |coding| {negotiation::coding_quality(ranges, *coding) > 0.0};
+		let candidates = ~[etag.to_owned()] + codings.map(|coding| {compression::coded_etag(etag, *coding)});
+		do vec::find(candidates) |candidate|
+		{
+			do tags.any |tag|
+			{
+				*tag == ~"*" || (if tag.starts_with("W/") {tag.slice(2, tag.len())} else {copy *tag}) == *candidate
+			}
+		}
+	}
+	else
+	{
+		match request.headers.find_date("If-Modified-Since")
+		{
+			option::Some(ref since) if modified <= since.to_timespec().sec => option::Some(etag.to_owned()),
+			_ => option::None,
+		}
+	}
+}
+
+priv fn accepts_gzip(request: &Request) -> bool
+{
+	let ranges = negotiation::parse_qvalues(request.headers.find("Accept-Encoding").get_default(~""));
+	negotiation::coding_quality(ranges, "gzip") > 0.0
+}
+
+pub fn is_text_file(config: &Config, path: &str) -> bool
+{
+	match rsrc_type(config, path)
+	{
+		option::Some(mime_type) => mime_type.starts_with(~"text/"),
+		option::None => false,
+	}
 }
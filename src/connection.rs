@@ -2,7 +2,29 @@
 //use socket::*;
 use core::path::{GenericPath};
 use core::send_map::linear::{LinearMap};
-use request::{process_request, make_header_and_body};
+use request::{prepare_response, process_bad_request, error_body, make_header_and_body, make_chunk, body_len, needs_chunking, parse_url};
+
+// Messages sent from the task reading requests to the task handling the connection.
+priv enum ReadMesg
+{
+	ReadRequest(http_parser::HttpRequest),
+	ReadIdle,						// keep_alive_timeout elapsed without a new request
+	ReadFailed(Status),				// status to send to the client before closing the connection
+	ReadBadRequest(~str),			// request couldn't be parsed, str is the reason
+	ReadContinue,					// client is waiting for a 100 Continue response before sending the body
+	ReadClosed,						// client closed the connection (or there was an error)
+}
+
+priv enum ReadError
+{
+	Closed,
+	IdleTimeout,
+	TimedOut,
+	Malformed(~str),
+	UrlTooLong,
+	HeadersTooLarge,
+	BodyTooLarge,
+}
 
 // TODO: probably want to use task::unsupervise
 pub fn handle_connection(config: &Config, fd: libc::c_int, local_addr: &str, remote_addr: &str)
@@ -15,218 +37,1196 @@ pub fn handle_connection(config: &Config, fd: libc::c_int, local_addr: &str, rem
 	
 	// read_requests needs to run on its own thread so it doesn't block this task. 
 	let ra = remote_addr.to_owned();
-	do task::spawn_sched(task::SingleThreaded) {read_requests(ra, fd, request_chan);}
+	let config2 = copy *config;
+	do task::spawn_sched(task::SingleThreaded) |move ra, move config2| {read_requests(&config2, ra, fd, request_chan);}
 	
 	let mut sse_tasks = LinearMap();
+	let mut stream = ChunkedWriter(sock);
+	let mut stream_path = ~"";				// path of the sse task whose event stream is open
 	loop
 	{
 		debug!("-----------------------------------------------------------");
 		match oldcomm::select2(request_port, sse_port)
 		{
-			either::Left(option::Some(move request)) =>
+			either::Left(ReadRequest(move request)) =>
+			{
+				let is_head = request.method == ~"HEAD";
+				let (path, _params) = parse_url(request.url);
+				let (response, body) = prepare_response(config, &mut sse_tasks, sse_chan, request, local_addr, remote_addr);
+				let close = closes_connection(&response.headers);
+				let chunked = is_chunked_response(&response.headers);
+				let (header, body) = make_header_and_body(&response, body);
+				
+				// Section 9.4 says that responses to HEAD requests must have the same headers
+				// as GET responses (including Content-Length) but must not have a body.
+				let body = if is_head {StringBody(@~"")} else {body};
+				let sent = if !is_head && chunked
+				{
+					// Event streams are left open until their sse task ends.
+					let is_event_stream = !needs_chunking(&body) && sse_tasks.contains_key(&path);
+					let sent = write_response(config, sock, header, StringBody(@~"")) && stream.start(config, body);
+					if sent && is_event_stream
+					{
+						stream_path = path;
+						true
+					}
+					else if sent
+					{
+						stream.finish(config)
+					}
+					else
+					{
+						false
+					}
+				}
+				else
+				{
+					write_response(config, sock, header, body)
+				};
+				if !sent
+				{
+					// This will cause read_requests to send ReadClosed.
+					posix::shutdown(fd, posix::SHUT_RDWR);
+				}
+				else if close
+				{
+					info!("closing connection to %s", remote_addr);
+					close_sses(&sse_tasks);
+					posix::shutdown(fd, posix::SHUT_RDWR);
+					break;
+				}
+			}
+			either::Left(ReadContinue) =>
 			{
-				let (header, body) = process_request(config, &mut sse_tasks, sse_chan, request, local_addr, remote_addr);
-				write_response(sock, header, body);
+				// Messages are handled in order so responses to earlier pipelined
+				// requests have already been written.
+				if !write_response(config, sock, fmt!("HTTP/1.1 %s\r\n\r\n", status::Continue.to_str()), StringBody(@~""))
+				{
+					posix::shutdown(fd, posix::SHUT_RDWR);
+				}
 			}
-			either::Left(option::None) =>
+			either::Left(ReadIdle) =>
+			{
+				// Event streams are long lived so we only time out connections without them.
+				if sse_tasks.is_empty()
+				{
+					info!("closing idle connection to %s", remote_addr);
+					posix::shutdown(fd, posix::SHUT_RDWR);
+				}
+			}
+			either::Left(ReadFailed(move status)) =>
+			{
+				info!("responding to %s with %s", remote_addr, status.to_str());
+				let (header, body) = make_error_response(config, status);
+				write_response(config, sock, header, body);
+				close_sses(&sse_tasks);
+				posix::shutdown(fd, posix::SHUT_RDWR);
+				break;
+			}
+			either::Left(ReadBadRequest(move mesg)) =>
+			{
+				info!("responding to %s with 400 Bad Request", remote_addr);
+				let (header, body) = process_bad_request(config, mesg, local_addr, remote_addr);
+				write_response(config, sock, header, body);
+				close_sses(&sse_tasks);
+				posix::shutdown(fd, posix::SHUT_RDWR);
+				break;
+			}
+			either::Left(ReadClosed) =>
 			{
 				close_sses(&sse_tasks);
 				break;
 			}
 			either::Right(move body) =>
 			{
-				let response = make_response(config);
-				let (_, body) = make_header_and_body(&response, StringBody(@body));
-				write_response(sock, ~"", body);
+				if !stream.is_open()
+				{
+					warn!("ignoring data pushed to %s after its event stream was finished", remote_addr);
+				}
+				else if body.is_empty()
+				{
+					// The sse task has ended so we can finish the event stream.
+					info!("finishing %s event stream to %s", stream_path, remote_addr);
+					sse_tasks.remove(&stream_path);
+					if !stream.finish(config)
+					{
+						posix::shutdown(fd, posix::SHUT_RDWR);
+					}
+				}
+				else if !stream.write(config, StringBody(@body))
+				{
+					posix::shutdown(fd, posix::SHUT_RDWR);
+				}
+			}
+		}
+	}
+}
+
+priv fn read_requests(config: &Config, remote_addr: &str, fd: libc::c_int, poke: oldcomm::Chan<ReadMesg>)
+{
+	let sock = @socket::socket::socket_handle(fd);		// socket::socket_handle(fd);
+	let parse = http_parser::make_parser();
+	let mut buffer = ReadBuffer();
+	loop
+	{
+		let mesg = read_request(config, remote_addr, sock, &mut buffer, parse, poke);
+		let (failed, done) =
+			match mesg
+			{
+				ReadRequest(_) | ReadIdle	=> (false, false),
+				ReadContinue				=> (false, false),
+				ReadFailed(_)				=> (true, true),
+				ReadBadRequest(_)			=> (true, true),
+				ReadClosed					=> (false, true),
+			};
+		oldcomm::send(poke, mesg);
+		
+		if failed
+		{
+			// Don't close the socket until handle_connection has had a chance to send
+			// the error response (it will shutdown the socket when it's done).
+			wait_for_shutdown(sock);
+		}
+		if done
+		{
+			// Client closed connection or there was some sort of error
+			// (in which case the client will re-open a connection).
+			info!("detached from %s", remote_addr);
+			break;
+		}
+	}
+}
+
+priv fn read_request(config: &Config, remote_addr: &str, sock: @socket::socket::socket_handle, buffer: &mut ReadBuffer, parse: fn@ (&str) -> result::Result<http_parser::HttpRequest, ~str>, poke: oldcomm::Chan<ReadMesg>) -> ReadMesg
+{
+	match read_headers(config, remote_addr, sock, buffer)
+	{
+		result::Ok(ref headers) =>
+		{
+			match parse(*headers)
+			{
+				result::Ok(ref request) =>
+				{
+					read_request_body(config, remote_addr, sock, buffer, request, poke)
+				}
+				result::Err(ref mesg) =>
+				{
+					error!("Couldn't parse: '%s' from %s", *mesg, remote_addr);
+					error!("%s", *headers);
+					ReadBadRequest(copy *mesg)
+				}
+			}
+		}
+		result::Err(err) =>
+		{
+			read_error_to_mesg(err)
+		}
+	}
+}
+
+priv fn read_request_body(config: &Config, remote_addr: &str, sock: @socket::socket::socket_handle, buffer: &mut ReadBuffer, request: &http_parser::HttpRequest, poke: oldcomm::Chan<ReadMesg>) -> ReadMesg
+{
+	// Section 3.6 says that servers should return 501 if they don't understand a transfer-coding.
+	if has_unknown_coding(request)
+	{
+		warn!("request from %s used an unsupported Transfer-Encoding", remote_addr);
+		return ReadFailed(status::NotImplemented);
+	}
+	
+	// Section 4.4 says that Transfer-Encoding takes precedence over Content-Length.
+	if is_chunked(request)
+	{
+		if !handle_expect(config, request, buffer, poke)
+		{
+			return ReadFailed(status::ExpectationFailed);
+		}
+		
+		match read_chunked_body(config, remote_addr, sock, buffer)
+		{
+			result::Ok((ref body, ref trailers)) =>
+			{
+				ReadRequest(add_chunked_body(request, body, *trailers))
+			}
+			result::Err(err) =>
+			{
+				read_error_to_mesg(err)
+			}
+		}
+	}
+	else if request.headers.contains_key("Content-Length")
+	{
+		// Check the length before handle_expect so that clients don't send bodies we'll reject.
+		let total_len = match content_length(config, remote_addr, request)
+		{
+			result::Ok(n) => n,
+			result::Err(err) => return read_error_to_mesg(err),
+		};
+		if !handle_expect(config, request, buffer, poke)
+		{
+			return ReadFailed(status::ExpectationFailed);
+		}
+		
+		match read_body(config, remote_addr, sock, buffer, total_len)
+		{
+			result::Ok(ref body) =>
+			{
+				ReadRequest(http_parser::HttpRequest {body: copy *body, ..copy *request})
+			}
+			result::Err(err) =>
+			{
+				read_error_to_mesg(err)
+			}
+		}
+	}
+	else
+	{
+		ReadRequest(copy *request)
+	}
+}
+
+// Section 8.2.3 says that clients may send "Expect: 100-continue" and wait for a
+// 100 Continue response before sending a request body. Returns false if the
+// request should be rejected with 417 Expectation Failed.
+priv fn handle_expect(config: &Config, request: &http_parser::HttpRequest, buffer: &ReadBuffer, poke: oldcomm::Chan<ReadMesg>) -> bool
+{
+	match request.headers.find("Expect")
+	{
+		option::Some(ref value) =>
+		{
+			if str::to_lower(str::trim(*value)) != ~"100-continue"
+			{
+				// Section 14.20 says that we must reject expectations we don't understand.
+				info!("rejecting expectation '%s'", *value);
+				false
+			}
+			else
+			{
+				let route = request::find_route_name(config, request.method, request.url);
+				if (config.expect_continue)(config, route, &request.headers)
+				{
+					// Clients may start sending the body without waiting for us in which
+					// case we don't need to send the 100 response. Note that we must not
+					// send a 100 response to HTTP/1.0 clients.
+					let is_1_0 = request.major_version == 1 && request.minor_version == 0;
+					if buffered_len(buffer) == 0u && !is_1_0
+					{
+						oldcomm::send(poke, ReadContinue);
+					}
+					true
+				}
+				else
+				{
+					info!("expect_continue rejected %s %s", request.method, request.url);
+					false
+				}
+			}
+		}
+		option::None =>
+		{
+			true
+		}
+	}
+}
+
+priv fn content_length(config: &Config, remote_addr: &str, request: &http_parser::HttpRequest) -> result::Result<uint, ReadError>
+{
+	let value = request.headers.get("Content-Length");
+	match uint::from_str(str::trim(value))
+	{
+		option::Some(n) =>
+		{
+			if config.max_body_length > 0u && n > config.max_body_length
+			{
+				warn!("content-length from %s was %? bytes", remote_addr, n);
+				result::Err(BodyTooLarge)
+			}
+			else
+			{
+				result::Ok(n)
+			}
+		}
+		option::None =>
+		{
+			warn!("bad content-length from %s: %s", remote_addr, value);
+			result::Err(Malformed(fmt!("bad Content-Length '%s'", value)))
+		}
+	}
+}
+
+priv fn read_error_to_mesg(err: ReadError) -> ReadMesg
+{
+	match err
+	{
+		Closed		=> ReadClosed,
+		IdleTimeout	=> ReadIdle,
+		TimedOut	=> ReadFailed(status::RequestTimeout),
+		Malformed(move mesg)	=> ReadBadRequest(mesg),
+		UrlTooLong	=> ReadFailed(status::RequestURITooLong),
+		HeadersTooLarge	=> ReadFailed(status::RequestHeaderFieldsTooLarge),
+		BodyTooLarge	=> ReadFailed(status::RequestEntityTooLarge),
+	}
+}
+
+priv fn wait_for_shutdown(sock: @socket::socket::socket_handle)
+{
+	loop
+	{
+		match socket::socket::recv(sock, 1024u)
+		{
+			result::Ok(ref result) if result.bytes > 0 => {}
+			_ => {break;}
+		}
+	}
+}
+
+// Clients can pipeline requests so a read from the socket may return the end
+// of one request along with the start of the next one. So we read whatever is
+// available into a ReadBuffer and split requests out of that.
+priv const READ_SIZE: uint = 4096u;
+
+priv struct ReadBuffer
+{
+	data: ~[u8],
+	start: uint,			// bytes before this have been consumed
+	scanned: uint,			// index of the next byte to check for the end of the headers
+}
+
+priv fn ReadBuffer() -> ReadBuffer
+{
+	ReadBuffer {data: ~[], start: 0u, scanned: 0u}
+}
+
+priv fn buffered_len(buffer: &ReadBuffer) -> uint
+{
+	buffer.data.len() - buffer.start
+}
+
+priv fn append_bytes(buffer: &mut ReadBuffer, bytes: &[u8])
+{
+	if buffer.start > 0u && buffer.start >= buffer.data.len()/2u
+	{
+		// Drop the bytes which have already been consumed (we don't do this
+		// for every request to avoid copying the unconsumed bytes too often).
+		buffer.data = vec::slice(buffer.data, buffer.start, buffer.data.len());
+		buffer.scanned -= buffer.start;
+		buffer.start = 0u;
+	}
+	vec::push_all(&mut buffer.data, bytes);
+}
+
+// If the buffer contains a complete set of headers then they are consumed and returned.
+priv fn take_headers(buffer: &mut ReadBuffer) -> option::Option<~[u8]>
+{
+	// Section 4.1 says that servers should ignore empty lines before the request line.
+	while buffered_len(buffer) >= 2u && buffer.data[buffer.start] == 0x0Du8 && buffer.data[buffer.start+1u] == 0x0Au8
+	{
+		buffer.start += 2u;
+	}
+	
+	let mut i = uint::max(buffer.scanned, buffer.start + 3u);
+	while i < buffer.data.len()
+	{
+		if buffer.data[i-3u] == 0x0Du8 && buffer.data[i-2u] == 0x0Au8 && buffer.data[i-1u] == 0x0Du8 && buffer.data[i] == 0x0Au8
+		{
+			let headers = vec::slice(buffer.data, buffer.start, i+1u);
+			buffer.start = i+1u;
+			buffer.scanned = buffer.start;
+			return option::Some(headers);
+		}
+		i += 1u;
+	}
+	buffer.scanned = i;
+	option::None
+}
+
+// Consumes and returns up to count bytes.
+priv fn take_bytes(buffer: &mut ReadBuffer, count: uint) -> ~[u8]
+{
+	let end = buffer.start + uint::min(count, buffered_len(buffer));
+	let bytes = vec::slice(buffer.data, buffer.start, end);
+	buffer.start = end;
+	buffer.scanned = uint::max(buffer.scanned, end);
+	bytes
+}
+
+priv fn has_line(buffer: &ReadBuffer) -> bool
+{
+	let mut i = buffer.start + 1u;
+	while i < buffer.data.len()
+	{
+		if buffer.data[i-1u] == 0x0Du8 && buffer.data[i] == 0x0Au8
+		{
+			return true;
+		}
+		i += 1u;
+	}
+	false
+}
+
+// If the buffer contains a CRLF then the line before it is consumed and returned
+// (without the CRLF).
+priv fn take_line(buffer: &mut ReadBuffer) -> option::Option<~[u8]>
+{
+	let mut i = buffer.start + 1u;
+	while i < buffer.data.len()
+	{
+		if buffer.data[i-1u] == 0x0Du8 && buffer.data[i] == 0x0Au8
+		{
+			let line = vec::slice(buffer.data, buffer.start, i-1u);
+			buffer.start = i+1u;
+			buffer.scanned = uint::max(buffer.scanned, buffer.start);
+			return option::Some(line);
+		}
+		i += 1u;
+	}
+	option::None
+}
+
+// Appends whatever data is available on the socket (up to max_bytes) to the buffer.
+priv fn fill_buffer(remote_addr: &str, sock: @socket::socket::socket_handle, buffer: &mut ReadBuffer, max_bytes: uint) -> result::Result<(), ReadError>
+{
+	match socket::socket::recv(sock, max_bytes)
+	{
+		result::Ok(ref result) =>
+		{
+			if result.bytes > 0
+			{
+				append_bytes(buffer, vec::slice(result.buffer, 0u, result.bytes));
+				result::Ok(())
+			}
+			else
+			{
+				// peer has closed its side of the connection
+				result::Err(Closed)
+			}
+		}
+		result::Err(ref mesg) =>
+		{
+			warn!("recv for %s failed with error: %s", remote_addr, *mesg);
+			result::Err(Closed)
+		}
+	}
+}
+
+// Before any bytes of a request arrive we wait for keep_alive_timeout. After
+// that the client has header_timeout to finish sending the headers.
+priv fn read_headers(config: &Config, remote_addr: &str, sock: @socket::socket::socket_handle, buffer: &mut ReadBuffer) -> result::Result<~str, ReadError>
+{
+	let idle_deadline = make_deadline(config.keep_alive_timeout);
+	let mut header_deadline = 0u64;
+	
+	loop
+	{
+		match take_headers(buffer)
+		{
+			option::Some(ref headers) =>
+			{
+				let result = check_header_limits(config, *headers);
+				if result.is_err()
+				{
+					warn!("headers from %s exceeded limits", remote_addr);
+					return result::Err(result.get_err());
+				}
+				return headers_to_str(*headers);
+			}
+			option::None =>
+			{
+			}
+		}
+		
+		// Don't let clients make us buffer arbitrarily large headers.
+		if config.max_header_bytes > 0u && buffered_len(buffer) > config.max_header_bytes
+		{
+			warn!("headers from %s exceeded %? bytes", remote_addr, config.max_header_bytes);
+			return result::Err(if has_line(buffer) {HeadersTooLarge} else {UrlTooLong});
+		}
+		
+		if buffered_len(buffer) == 0u
+		{
+			match wait_readable(sock, idle_deadline)
+			{
+				result::Ok(true) =>
+				{
+				}
+				result::Ok(false) =>
+				{
+					return result::Err(IdleTimeout);
+				}
+				result::Err(ref mesg) =>
+				{
+					error!("read_headers for %s failed: %s", remote_addr, *mesg);
+					return result::Err(Closed);
+				}
+			}
+		}
+		else
+		{
+			if header_deadline == 0u64
+			{
+				header_deadline = make_deadline(config.header_timeout);
+			}
+			match wait_readable(sock, header_deadline)
+			{
+				result::Ok(true) =>
+				{
+				}
+				result::Ok(false) =>
+				{
+					warn!("read_headers for %s timed out", remote_addr);
+					return result::Err(TimedOut);
+				}
+				result::Err(ref mesg) =>
+				{
+					error!("read_headers for %s failed: %s", remote_addr, *mesg);
+					return result::Err(Closed);
+				}
+			}
+		}
+		
+		let result = fill_buffer(remote_addr, sock, buffer, READ_SIZE);
+		if result.is_err()
+		{
+			return result::Err(result.get_err());
+		}
+	}
+}
+
+priv fn check_header_limits(config: &Config, headers: &[u8]) -> result::Result<(), ReadError>
+{
+	// The request line is: method SP url SP version CRLF
+	let mut line_end = 0u;
+	while headers[line_end] != 0x0Du8
+	{
+		line_end += 1u;
+	}
+	let mut url_start = 0u;
+	while url_start < line_end && headers[url_start] != ' ' as u8
+	{
+		url_start += 1u;
+	}
+	let mut url_end = line_end;
+	while url_end > url_start && headers[url_end - 1u] != ' ' as u8
+	{
+		url_end -= 1u;
+	}
+	let url_len = if url_end > url_start + 1u {url_end - url_start - 2u} else {0u};
+	
+	// Every header ends with a CRLF as do the request line and the headers themselves.
+	// Note that this will count continuation lines as headers.
+	let count = do vec::foldl(0u, headers) |count, b| {if *b == 0x0Au8 {count + 1u} else {count}};
+	
+	if config.max_url_length > 0u && url_len > config.max_url_length
+	{
+		result::Err(UrlTooLong)
+	}
+	else if config.max_header_bytes > 0u && vec::len(headers) > config.max_header_bytes
+	{
+		result::Err(HeadersTooLarge)
+	}
+	else if config.max_header_count > 0u && count - 2u > config.max_header_count
+	{
+		result::Err(HeadersTooLarge)
+	}
+	else
+	{
+		result::Ok(())
+	}
+}
+
+priv fn headers_to_str(headers: &[u8]) -> result::Result<~str, ReadError>
+{
+	if str::is_utf8(headers)
+	{
+		let headers = str::from_bytes(headers);
+		debug!("headers: %s", headers);
+		result::Ok(headers)
+	}
+	else
+	{
+		// Section 2.2 says that headers are octets but we only accept utf-8 (which
+		// includes ASCII) so that requests can be parsed as strings.
+		warn!("Headers were not utf-8");
+		result::Err(Malformed(~"headers were not utf-8"))
+	}
+}
+
+// body_timeout is the time allowed between reads so that large uploads over
+// slow connections don't time out.
+priv fn read_body(config: &Config, remote_addr: &str, sock: @socket::socket::socket_handle, buffer: &mut ReadBuffer, total_len: uint) -> result::Result<~[u8], ReadError>
+{
+	let mut body = take_bytes(buffer, total_len);
+	while vec::len(body) < total_len 
+	{
+		match wait_readable(sock, make_deadline(config.body_timeout))
+		{
+			result::Ok(true) =>
+			{
+			}
+			result::Ok(false) =>
+			{
+				warn!("read_body for %s timed out", remote_addr);
+				return result::Err(TimedOut);
+			}
+			result::Err(ref mesg) =>
+			{
+				error!("read_body for %s failed: %s", remote_addr, *mesg);
+				return result::Err(Closed);
+			}
+		}
+		
+		let result = fill_buffer(remote_addr, sock, buffer, uint::min(uint::max(total_len - vec::len(body), READ_SIZE), 16u*READ_SIZE));
+		if result.is_err()
+		{
+			return result::Err(result.get_err());
+		}
+		vec::push_all(&mut body, take_bytes(buffer, total_len - vec::len(body)));
+	}
+	
+	debug!("body has %? bytes", vec::len(body));
+	result::Ok(body)
+}
+
+priv fn is_chunked(request: &http_parser::HttpRequest) -> bool
+{
+	request.headers.transfer_codings().contains(&~"chunked")
+}
+
+// chunked is the only transfer-coding we decode so we can't hand bodies which use
+// other codings (e.g. "gzip, chunked") to views.
+priv fn has_unknown_coding(request: &http_parser::HttpRequest) -> bool
+{
+	do request.headers.transfer_codings().any |coding|
+	{
+		*coding != ~"chunked" && *coding != ~"identity"
+	}
+}
+
+// Per the pseudo-code in section 19.4.6 the decoded request has a Content-Length
+// header instead of a chunked Transfer-Encoding and the trailers become headers.
+priv fn add_chunked_body(request: &http_parser::HttpRequest, body: &~[u8], trailers: &[(~str, ~str)]) -> http_parser::HttpRequest
+{
+	let mut headers = copy request.headers;
+	for trailers.each |trailer|
+	{
+		let (name, value) = copy *trailer;
+		headers.add(name, value);
+	}
+	headers.remove("Transfer-Encoding");
+	headers.set_content_length(vec::len(*body));
+	
+	http_parser::HttpRequest {headers: headers, body: copy *body, ..copy *request}
+}
+
+// Reads a body using the chunked transfer coding (see section 3.6.1).
+// Returns the body along with any trailer headers.
+priv fn read_chunked_body(config: &Config, remote_addr: &str, sock: @socket::socket::socket_handle, buffer: &mut ReadBuffer) -> result::Result<(~[u8], ~[(~str, ~str)]), ReadError>
+{
+	let mut state = ChunkSize;
+	let mut body = ~[];
+	let mut trailers = ~[];
+	loop
+	{
+		let result = decode_chunks(buffer, config.max_header_bytes, &mut state, &mut body, &mut trailers);
+		if config.max_body_length > 0u && vec::len(body) > config.max_body_length
+		{
+			warn!("chunked body from %s exceeded %? bytes", remote_addr, config.max_body_length);
+			return result::Err(BodyTooLarge);
+		}
+		if config.max_header_count > 0u && vec::len(trailers) > config.max_header_count
+		{
+			warn!("chunked body from %s had too many trailers", remote_addr);
+			return result::Err(HeadersTooLarge);
+		}
+		
+		match result
+		{
+			result::Ok(true) =>
+			{
+				debug!("chunked body has %? bytes", vec::len(body));
+				return result::Ok((body, trailers));
+			}
+			result::Ok(false) =>
+			{
+				match wait_readable(sock, make_deadline(config.body_timeout))
+				{
+					result::Ok(true) =>
+					{
+					}
+					result::Ok(false) =>
+					{
+						warn!("read_chunked_body for %s timed out", remote_addr);
+						return result::Err(TimedOut);
+					}
+					result::Err(ref mesg) =>
+					{
+						error!("read_chunked_body for %s failed: %s", remote_addr, *mesg);
+						return result::Err(Closed);
+					}
+				}
+				
+				let result = fill_buffer(remote_addr, sock, buffer, READ_SIZE);
+				if result.is_err()
+				{
+					return result::Err(result.get_err());
+				}
+			}
+			result::Err(move err) =>
+			{
+				error!("Bad chunked body from %s: %?", remote_addr, err);
+				return result::Err(err);
 			}
 		}
 	}
 }
 
-priv fn read_requests(remote_addr: &str, fd: libc::c_int, poke: oldcomm::Chan<option::Option<http_parser::HttpRequest>>)
+priv enum ChunkState
+{
+	ChunkSize,				// expecting chunk-size [chunk-extension] CRLF
+	ChunkData(uint),		// expecting this many more bytes of chunk-data
+	ChunkEnd,				// expecting the CRLF after chunk-data
+	ChunkTrailer,			// expecting a trailer header or the final CRLF
+}
+
+// Decodes as much of a chunked body as is in the buffer. Returns true once the
+// last chunk and trailer have been consumed and false if more data is needed.
+// Chunk-size and trailer lines may be at most max_line bytes (zero disables the limit).
+priv fn decode_chunks(buffer: &mut ReadBuffer, max_line: uint, state: &mut ChunkState, body: &mut ~[u8], trailers: &mut ~[(~str, ~str)]) -> result::Result<bool, ReadError>
+{
+	loop
+	{
+		match *state
+		{
+			ChunkSize =>
+			{
+				match take_limited_line(buffer, max_line)
+				{
+					result::Ok(option::Some(ref line)) =>
+					{
+						if !str::is_utf8(*line)
+						{
+							return result::Err(Malformed(~"chunk size wasn't utf-8"));
+						}
+						
+						let line = str::from_bytes(*line);
+						let size = match str::find_char(line, ';')
+						{
+							option::Some(i) => line.slice(0, i),
+							option::None => copy line,
+						};
+						match uint::from_str_radix(str::trim(size), 16u)
+						{
+							option::Some(0u) => *state = ChunkTrailer,
+							option::Some(n) => *state = ChunkData(n),
+							option::None => return result::Err(Malformed(fmt!("bad chunk size '%s'", line))),
+						}
+					}
+					result::Ok(option::None) =>
+					{
+						return result::Ok(false);
+					}
+					result::Err(move err) =>
+					{
+						return result::Err(err);
+					}
+				}
+			}
+			ChunkData(remaining) =>
+			{
+				let bytes = take_bytes(buffer, remaining);
+				let count = vec::len(bytes);
+				vec::push_all(body, bytes);
+				if count < remaining
+				{
+					*state = ChunkData(remaining - count);
+					return result::Ok(false);
+				}
+				*state = ChunkEnd;
+			}
+			ChunkEnd =>
+			{
+				if buffered_len(buffer) < 2u
+				{
+					return result::Ok(false);
+				}
+				let crlf = take_bytes(buffer, 2u);
+				if crlf[0] != 0x0Du8 || crlf[1] != 0x0Au8
+				{
+					return result::Err(Malformed(~"chunk data wasn't followed by CRLF"));
+				}
+				*state = ChunkSize;
+			}
+			ChunkTrailer =>
+			{
+				match take_limited_line(buffer, max_line)
+				{
+					result::Ok(option::Some(ref line)) =>
+					{
+						if vec::is_empty(*line)
+						{
+							return result::Ok(true);
+						}
+						else if !str::is_utf8(*line)
+						{
+							return result::Err(Malformed(~"trailer wasn't utf-8"));
+						}
+						
+						let line = str::from_bytes(*line);
+						match str::find_char(line, ':')
+						{
+							option::Some(i) =>
+							{
+								vec::push(trailers, (str::to_lower(str::trim(line.slice(0, i))), str::trim(line.slice(i+1, line.len()))));
+							}
+							option::None =>
+							{
+								return result::Err(Malformed(fmt!("bad trailer '%s'", line)));
+							}
+						}
+					}
+					result::Ok(option::None) =>
+					{
+						return result::Ok(false);
+					}
+					result::Err(move err) =>
+					{
+						return result::Err(err);
+					}
+				}
+			}
+		}
+	}
+}
+
+// Like take_line except that clients which send lines longer than max_line (or which
+// never send a CRLF) get an error instead of filling up the buffer.
+priv fn take_limited_line(buffer: &mut ReadBuffer, max_line: uint) -> result::Result<option::Option<~[u8]>, ReadError>
+{
+	match take_line(buffer)
+	{
+		option::Some(move line) =>
+		{
+			if max_line > 0u && line.len() > max_line
+			{
+				result::Err(HeadersTooLarge)
+			}
+			else
+			{
+				result::Ok(option::Some(line))
+			}
+		}
+		option::None =>
+		{
+			if max_line > 0u && buffered_len(buffer) > max_line + 2u
+			{
+				result::Err(HeadersTooLarge)
+			}
+			else
+			{
+				result::Ok(option::None)
+			}
+		}
+	}
+}
+
+// Returns the time (in ms) at which timeout will expire or zero if the timeout is disabled.
+priv fn make_deadline(timeout: uint) -> u64
+{
+	if timeout > 0u
+	{
+		std::time::precise_time_ns()/1_000_000u64 + timeout as u64
+	}
+	else
+	{
+		0u64
+	}
+}
+
+// Returns false if the deadline passes before there is data to read from sock.
+priv fn wait_readable(sock: @socket::socket::socket_handle, deadline: u64) -> result::Result<bool, ~str>
+{
+	if deadline > 0u64
+	{
+		let now = std::time::precise_time_ns()/1_000_000u64;
+		if now < deadline
+		{
+			posix::wait_for(sock.sockfd, posix::POLLIN, (deadline - now) as uint)
+		}
+		else
+		{
+			result::Ok(false)
+		}
+	}
+	else
+	{
+		result::Ok(true)
+	}
+}
+
+// process_request adds a "Connection: close" header if the client doesn't want
+// a persistent connection (views may also add the header).
+priv fn closes_connection(headers: &Headers) -> bool
 {
-	let sock = @socket::socket::socket_handle(fd);		// socket::socket_handle(fd);
-	let parse = http_parser::make_parser();
-	loop
+	headers.connection().contains(&~"close")
+}
+
+priv fn is_chunked_response(headers: &Headers) -> bool
+{
+	headers.transfer_codings().contains(&~"chunked")
+}
+
+// Used when we can't read the client's request. The connection is closed after this is sent.
+priv fn make_error_response(config: &Config, status: Status) -> (~str, Body)
+{
+	let headers = headers_from_vector(~[
+		(~"Connection", ~"close"),
+		(~"Content-Type", ~"text/html; charset=UTF-8"),
+		(~"Date", std::time::now_utc().rfc822()),
+		(~"Server", copy config.server_info),
+	]);
+	let body = error_body(&status);
+	
+	let response = Response {status: status, headers: headers, body: StringBody(@~""), template: ~"", context: std::map::HashMap()};
+	make_header_and_body(&response, StringBody(@body))
+}
+
+// TODO: presumbably when we switch to a better socket library we'll be able to handle errors here...
+// Returns false if the response could not be sent (e.g. the write timed out).
+priv fn write_response(config: &Config, sock: @socket::socket::socket_handle, header: ~str, body: Body) -> bool unsafe
+{
+	let sent = do str::as_buf(header) |buffer, _len| {send_bytes(config, sock, buffer, header.len())};
+	sent && write_body(config, sock, &body)
+}
+
+priv fn write_body(config: &Config, sock: @socket::socket::socket_handle, body: &Body) -> bool unsafe
+{
+	match *body
 	{
-		let mut ok = false;
-		let headers = read_headers(remote_addr, sock);
-		if str::is_not_empty(headers)
+		StringBody(text) =>
+		{
+			do str::as_buf(*text) |buffer, _len| {send_bytes(config, sock, buffer, text.len())}
+		}
+		BinaryBody(binary) =>
+		{
+			send_bytes(config, sock, vec::raw::to_ptr(*binary), binary.len())
+		}
+		CompoundBody(parts) =>
 		{
-			match parse(headers)
+			for parts.each |part|
 			{
-				result::Ok(ref request) =>
-				{
-					if request.headers.contains_key(&~"content-length")
-					{
-						let body = read_body(sock, request.headers.get(&~"content-length"));
-						if str::is_not_empty(body)
-						{
-							oldcomm::send(poke, option::Some(http_parser::HttpRequest {body: body, ..copy *request}));
-							ok = true;
-						}
-					}
-					else
-					{
-						oldcomm::send(poke, option::Some(copy *request));
-						ok = true;
-					}
-				}
-				result::Err(ref mesg) =>
+				if !write_body(config, sock, *part)
 				{
-					error!("Couldn't parse: '%s' from %s", *mesg, remote_addr);
-					error!("%s", headers);
+					return false;
 				}
+			};
+			true
+		}
+		ReaderBody(reader, offset, len) =>
+		{
+			write_reader(config, sock, reader, offset, len)
+		}
+		ProducerBody(producer) =>
+		{
+			// Only HTTP/1.0 clients get produced bodies without chunk framing.
+			do each_produced_chunk(producer) |chunk|
+			{
+				send_bytes(config, sock, vec::raw::to_ptr(chunk), chunk.len())
 			}
 		}
-		if !ok
+		TrailedBody(body, _names, _trailers) =>
 		{
-			// Client closed connection or there was some sort of error
-			// (in which case the client will re-open a connection).
-			info!("detached from %s", remote_addr);
-			oldcomm::send(poke, option::None);
-			break;
+			// Trailers can only be sent using the chunked transfer-coding.
+			write_body(config, sock, body)
 		}
 	}
 }
 
-// TODO: We can't simply do a read for whatever is available because
-// clients can issue multple requests. So we need to read the request
-// byte by byte until we get a double new-line. If this becomes a bottle
-// neck we could do chunked reads, but we'd need to take care to properly
-// handle multi-byte utf-8 characters and the split between headers and
-// the body.
-priv fn read_headers(remote_addr: &str, sock: @socket::socket::socket_handle) -> ~str unsafe
+// Calls blk with each chunk until blk returns false or the producer finishes or fails.
+// Returns true if the producer finished.
+priv fn each_produced_chunk(producer: BodyProducer, blk: fn(chunk: ~[u8]) -> bool) -> bool
 {
-	let mut buffer = ~[];
-	
-	while !found_headers(buffer) 
+	loop
 	{
-		match socket::socket::recv(sock, 1u)			// TODO: need a timeout
+		match producer()
 		{
-			result::Ok(ref result) =>
+			result::Ok(option::Some(move chunk)) =>
 			{
-				if result.bytes > 0
-				{
-					vec::push(&mut buffer, result.buffer[0]);
-				}
-				else
+				if !blk(chunk)
 				{
-					// peer has closed its side of the connection
-					return ~"";
+					return false;
 				}
 			}
+			result::Ok(option::None) =>
+			{
+				return true;
+			}
 			result::Err(ref mesg) =>
 			{
-				warn!("read_headers for %s failed with error: %s", remote_addr, *mesg);
-				return ~"";
+				error!("failed to produce body: %s", *mesg);
+				return false;
 			}
 		}
 	}
-	vec::push(&mut buffer, 0);		// must be null terminated
+}
+
+priv fn write_reader(config: &Config, sock: @socket::socket::socket_handle, reader: io::Reader, offset: uint, len: uint) -> bool unsafe
+{
+	reader.seek(offset as int, io::SeekSet);
 	
-	if str::is_utf8(buffer)
-	{
-		let c_str = cast::reinterpret_cast(&vec::raw::to_ptr(buffer));
-		let headers = str::raw::from_c_str(c_str);
-		debug!("headers: %s", headers);
-		headers
-	}
-	else
+	let mut remaining = len;
+	while remaining > 0u
 	{
-		error!("Headers were not utf-8");	// TODO: what does the standard say about encodings? do we need to negotiate? or at least return some error response...
-		~""
+		// If the file shrank we have no way to tell the client so the connection is closed.
+		let chunk = reader.read_bytes(uint::min(remaining, 64u*1024u));
+		if chunk.is_empty() || !send_bytes(config, sock, vec::raw::to_ptr(chunk), chunk.len())
+		{
+			return false;
+		}
+		remaining -= chunk.len();
 	}
+	true
 }
 
-priv fn found_headers(buffer: &[u8]) -> bool
+// Writes response bodies using the chunked transfer-coding (see section 3.6.1). Event
+// streams are written a chunk at a time as their sse task pushes data so the writer
+// tracks whether the current response is still open. Trailers from TrailedBody's are
+// collected as the body is written and sent with the last chunk.
+priv struct ChunkedWriter
 {
-	if vec::len(buffer) < 4u
-	{
-		false
-	}
-	else
-	{
-		let len = vec::len(buffer);
-		buffer[len-4u] == 0x0Du8 && buffer[len-3u] == 0x0Au8 && buffer[len-2u] == 0x0Du8 && buffer[len-1u] == 0x0Au8
-	}
+	priv sock: @socket::socket::socket_handle,
+	priv open: bool,
+	priv trailers: Headers,
+}
+
+priv fn ChunkedWriter(sock: @socket::socket::socket_handle) -> ChunkedWriter
+{
+	ChunkedWriter {sock: sock, open: false, trailers: Headers()}
 }
 
-priv fn read_body(sock: @socket::socket::socket_handle, content_length: ~str) -> ~str unsafe
+impl ChunkedWriter
 {
-	let total_len = option::get(uint::from_str(content_length));
+	fn is_open(&self) -> bool
+	{
+		self.open
+	}
 	
-	let mut buffer = ~[];
-	vec::reserve(&mut buffer, total_len);
+	// Starts a new chunked body with body as its first chunk (or, for a ProducerBody,
+	// all of the chunks).
+	fn start(&mut self, config: &Config, body: Body) -> bool
+	{
+		if self.open
+		{
+			warn!("starting a chunked body before the previous one was finished");
+		}
+		self.open = true;
+		self.write(config, body)
+	}
 	
-	while vec::len(buffer) < total_len 
+	// Empty bodies are skipped because a zero length chunk ends the body.
+	fn write(&mut self, config: &Config, body: Body) -> bool
 	{
-		match socket::socket::recv(sock, total_len - vec::len(buffer))			// TODO: need a timeout
+		assert self.open;
+		match body
 		{
-			result::Ok(ref result) =>
+			ProducerBody(producer) =>
 			{
-				if result.bytes > 0
+				do each_produced_chunk(producer) |chunk|
 				{
-					let mut i = 0u;
-					while i < result.bytes
-					{
-						vec::push(&mut buffer, result.buffer[i]);
-						i += 1u;
-					}
+					self.write(config, BinaryBody(@chunk))
 				}
-				else
+			}
+			TrailedBody(inner, _names, trailers) =>
+			{
+				if !self.write(config, copy *inner)
 				{
-					// peer has closed its side of the connection
-					return ~"";
+					return false;
 				}
+				let produced = trailers();
+				for produced.each |name, value|
+				{
+					self.trailers.add(copy *name, copy *value);
+				};
+				true
 			}
-			result::Err(ref mesg) =>
+			CompoundBody(parts) if needs_chunking(&body) =>
+			{
+				for parts.each |part|
+				{
+					if !self.write(config, copy **part)
+					{
+						return false;
+					}
+				};
+				true
+			}
+			_ =>
 			{
-				warn!("read_body failed with error: %s", *mesg);
-				return ~"";
+				body_len(&body) == 0u || write_body(config, self.sock, &make_chunk(body))
 			}
 		}
 	}
-	vec::push(&mut buffer, 0);		// must be null terminated
 	
-	if str::is_utf8(buffer)
+	// Writes the last chunk along with any trailers.
+	fn finish(&mut self, config: &Config) -> bool
 	{
-		let body = str::raw::from_buf(vec::raw::to_ptr(buffer));
-		debug!("body: %s", body);	// note that the log macros truncate really long strings 
-		body
+		assert self.open;
+		let last = make_last_chunk(&self.trailers);
+		self.open = false;
+		self.trailers = Headers();
+		write_body(config, self.sock, &StringBody(@last))
 	}
-	else
+}
+
+priv fn make_last_chunk(trailers: &Headers) -> ~str
+{
+	let mut text = ~"0\r\n";
+	for trailers.each |name, value|
 	{
-		error!("Body was not utf-8");	// TODO: what does the standard say about encodings? do we need to negotiate? or at least return some error response...
-		~""
-	}
+		text += fmt!("%s: %s\r\n", *name, *value);
+	};
+	text + ~"\r\n"
 }
 
-// TODO: check connection: keep-alive
-// TODO: presumbably when we switch to a better socket library we'll be able to handle errors here...
-priv fn write_response(sock: @socket::socket::socket_handle, header: ~str, body: Body) unsafe
+// Sends the bytes in blocks so that a client which stops reading trips write_timeout.
+priv fn send_bytes(config: &Config, sock: @socket::socket::socket_handle, buffer: *u8, len: uint) -> bool unsafe
 {
-	fn write_body(sock: @socket::socket::socket_handle, body: &Body) unsafe
+	// The socket is written without blocking so that a client which stops reading
+	// can't stall us for longer than write_timeout.
+	let mut offset = 0u;
+	while offset < len
 	{
-		match *body
+		match posix::send_some(sock.sockfd, ptr::offset(buffer, offset), len - offset)
 		{
-			StringBody(text) =>
+			result::Ok(0u) =>
 			{
-				do str::as_buf(*text) |buffer, _len| 	{socket::socket::send_buf(sock, buffer, text.len())};
+				match posix::wait_for(sock.sockfd, posix::POLLOUT, config.write_timeout)
+				{
+					result::Ok(true) =>
+					{
+					}
+					result::Ok(false) =>
+					{
+						warn!("write to client timed out");
+						return false;
+					}
+					result::Err(ref mesg) =>
+					{
+						error!("failed waiting to write to client: %s", *mesg);
+						return false;
+					}
+				}
 			}
-			BinaryBody(binary) =>
+			result::Ok(count) =>
 			{
-				socket::socket::send_buf(sock, vec::raw::to_ptr(*binary), binary.len());
+				offset += count;
 			}
-			CompoundBody(parts) =>
+			result::Err(ref mesg) =>
 			{
-				for parts.each |part| {write_body(sock, *part)};
+				warn!("failed writing to client: %s", *mesg);
+				return false;
 			}
 		}
 	}
-	
-	do str::as_buf(header) |buffer, _len| {socket::socket::send_buf(sock, buffer, header.len())};
-	write_body(sock, &body);
+	true
 }
 
 priv fn validate_config(config: &Config) -> ~str
@@ -266,6 +1266,18 @@ priv fn validate_config(config: &Config) -> ~str
 		vec::push(&mut errors, ~"resources_root is not a directory.");
 	}
 	
+	for config.mounts.each |mount|
+	{
+		if !mount.prefix.starts_with("/")
+		{
+			vec::push(&mut errors, fmt!("Mount prefix %s doesn't start with a slash.", mount.prefix));
+		}
+		if !os::path_is_dir(&mount.root)
+		{
+			vec::push(&mut errors, fmt!("Mount root %s is not a directory.", mount.root.to_str()));
+		}
+	}
+	
 	let mut names = ~[];
 	for vec::each(~[~"forbidden.html", ~"home.html", ~"not-found.html", ~"not-supported.html"]) |name|
 	{
@@ -367,3 +1379,341 @@ fn root_must_have_required_files()
 	assert validate_config(&config) == ~"Missing required files: forbidden.html, home.html, not-found.html, not-supported.html";
 }
 
+#[test]
+fn mounts_must_be_directories()
+{
+	let config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		mounts: ~[Mount(~"/static", GenericPath::from_str(~"server/src")), Mount(~"/missing", GenericPath::from_str(~"server/no-such-dir"))],
+		routes: ~[Route( ~"home", ~"GET", ~"/")],
+		views: utils::linear_map_from_vector(~[(~"home",  missing_view)]),
+		..initialize_config()};
+		
+	assert validate_config(&config) == ~"Mount root server/no-such-dir is not a directory.";
+}
+
+
+#[test]
+fn split_headers()
+{
+	let mut buffer = ReadBuffer();
+	append_bytes(&mut buffer, str::to_bytes(~"GET / HTTP/1.1\r\nHost: xxx\r"));
+	assert take_headers(&mut buffer).is_none();
+	
+	append_bytes(&mut buffer, str::to_bytes(~"\n\r"));
+	assert take_headers(&mut buffer).is_none();
+	
+	append_bytes(&mut buffer, str::to_bytes(~"\nGET /foo"));
+	let headers = take_headers(&mut buffer);
+	assert utils::check_strs(str::from_bytes(option::get(headers)), ~"GET / HTTP/1.1\r\nHost: xxx\r\n\r\n");
+	assert utils::check_strs(str::from_bytes(take_bytes(&mut buffer, 100u)), ~"GET /foo");
+	assert buffered_len(&buffer) == 0u;
+}
+
+#[test]
+fn pipelined_requests()
+{
+	let count = 1000u;
+	let mut input = ~"";
+	for uint::range(0u, count) |i|
+	{
+		if i % 2u == 0u
+		{
+			input += fmt!("GET /item/%? HTTP/1.1\r\nHost: localhost\r\n\r\n", i);
+		}
+		else
+		{
+			let body = fmt!("body %?", i);
+			input += fmt!("\r\nPOST /item/%? HTTP/1.1\r\nHost: localhost\r\nContent-Length: %?\r\n\r\n%s", i, body.len(), body);
+		}
+	}
+	
+	// Feed the input in READ_SIZE blocks to mimic reads from a socket.
+	let bytes = str::to_bytes(input);
+	let parse = http_parser::make_parser();
+	let mut buffer = ReadBuffer();
+	let mut offset = 0u;
+	let mut found = 0u;
+	let start = std::time::precise_time_ns();
+	while offset < bytes.len() || buffered_len(&buffer) > 0u
+	{
+		match take_headers(&mut buffer)
+		{
+			option::Some(ref headers) =>
+			{
+				let request = result::unwrap(parse(str::from_bytes(*headers)));
+				assert utils::check_strs(request.url, fmt!("/item/%?", found));
+				if request.headers.contains_key("Content-Length")
+				{
+					let len = option::get(request.headers.content_length());
+					let mut body = take_bytes(&mut buffer, len);
+					while body.len() < len
+					{
+						let end = uint::min(offset + READ_SIZE, bytes.len());
+						append_bytes(&mut buffer, vec::slice(bytes, offset, end));
+						offset = end;
+						vec::push_all(&mut body, take_bytes(&mut buffer, len - body.len()));
+					}
+					assert utils::check_strs(str::from_bytes(body), fmt!("body %?", found));
+				}
+				found += 1u;
+			}
+			option::None =>
+			{
+				let end = uint::min(offset + READ_SIZE, bytes.len());
+				append_bytes(&mut buffer, vec::slice(bytes, offset, end));
+				offset = end;
+			}
+		}
+	}
+	let elapsed = (std::time::precise_time_ns() - start)/1_000_000u64;
+	
+	assert found == count;
+	info!("parsed %? pipelined requests (%? bytes) in %? ms", count, bytes.len(), elapsed);
+}
+
+#[test]
+fn chunked_body()
+{
+	let mut buffer = ReadBuffer();
+	let mut state = ChunkSize;
+	let mut body = ~[];
+	let mut trailers = ~[];
+	
+	// Feed the body in pieces which split the chunk framing.
+	let pieces = ~[~"4\r\nWi", ~"ki\r", ~"\n5;name=value\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n0\r\nContent-MD5: abc\r\n", ~"\r\nGET / HTTP/1.1"];
+	for pieces.eachi |i, piece|
+	{
+		append_bytes(&mut buffer, str::to_bytes(*piece));
+		let done = result::unwrap(decode_chunks(&mut buffer, 100u, &mut state, &mut body, &mut trailers));
+		assert done == (i + 1u == pieces.len());
+	}
+	
+	assert utils::check_strs(str::from_bytes(body), ~"Wikipedia in\r\n\r\nchunks.");
+	assert utils::check_vectors(trailers, ~[(~"content-md5", ~"abc")]);
+	assert utils::check_strs(str::from_bytes(take_bytes(&mut buffer, 100u)), ~"GET / HTTP/1.1");
+}
+
+#[test]
+fn transfer_codings()
+{
+	let parse = http_parser::make_parser();
+	
+	let request = result::unwrap(parse(~"POST /comment HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"));
+	assert is_chunked(&request);
+	assert !has_unknown_coding(&request);
+	
+	let request = result::unwrap(parse(~"POST /comment HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"));
+	assert is_chunked(&request);
+	assert has_unknown_coding(&request);
+	
+	let request = result::unwrap(parse(~"POST /comment HTTP/1.1\r\nContent-Length: 10\r\n\r\n"));
+	assert !is_chunked(&request);
+	assert !has_unknown_coding(&request);
+}
+
+#[test]
+fn bad_chunk_size()
+{
+	let mut buffer = ReadBuffer();
+	let mut state = ChunkSize;
+	let mut body = ~[];
+	let mut trailers = ~[];
+	
+	append_bytes(&mut buffer, str::to_bytes(~"zz\r\nabc\r\n0\r\n\r\n"));
+	assert decode_chunks(&mut buffer, 100u, &mut state, &mut body, &mut trailers).is_err();
+	
+	// Non utf-8 lines are rejected instead of failing the task.
+	let mut buffer = ReadBuffer();
+	let mut state = ChunkSize;
+	append_bytes(&mut buffer, ~[0x34u8, 0xFFu8, 0x0Du8, 0x0Au8]);
+	assert decode_chunks(&mut buffer, 100u, &mut state, &mut body, &mut trailers).is_err();
+	
+	let mut buffer = ReadBuffer();
+	let mut state = ChunkSize;
+	append_bytes(&mut buffer, str::to_bytes(~"0\r\nX-Trailer: "));
+	append_bytes(&mut buffer, ~[0xC3u8, 0x28u8, 0x0Du8, 0x0Au8, 0x0Du8, 0x0Au8]);
+	assert decode_chunks(&mut buffer, 100u, &mut state, &mut body, &mut trailers).is_err();
+}
+
+#[test]
+fn long_chunk_lines()
+{
+	let mut buffer = ReadBuffer();
+	let mut state = ChunkSize;
+	let mut body = ~[];
+	let mut trailers = ~[];
+	
+	// Lines without a CRLF can't grow the buffer without bound.
+	append_bytes(&mut buffer, str::to_bytes(str::repeat("0", 20u)));
+	assert result::unwrap(decode_chunks(&mut buffer, 30u, &mut state, &mut body, &mut trailers)) == false;
+	append_bytes(&mut buffer, str::to_bytes(str::repeat("0", 20u)));
+	match decode_chunks(&mut buffer, 30u, &mut state, &mut body, &mut trailers)
+	{
+		result::Err(HeadersTooLarge) => {}
+		result => fail fmt!("expected HeadersTooLarge but found %?", result),
+	}
+	
+	let mut buffer = ReadBuffer();
+	let mut state = ChunkSize;
+	append_bytes(&mut buffer, str::to_bytes(~"0\r\nX-Trailer: " + str::repeat("x", 40u) + ~"\r\n\r\n"));
+	match decode_chunks(&mut buffer, 30u, &mut state, &mut body, &mut trailers)
+	{
+		result::Err(HeadersTooLarge) => {}
+		result => fail fmt!("expected HeadersTooLarge but found %?", result),
+	}
+}
+
+#[test]
+fn non_utf8_headers()
+{
+	let config = Config {
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		load_rsrc: |_path| {result::Err(~"not loaded")},
+		..initialize_config()};
+	
+	let mut buffer = ReadBuffer();
+	append_bytes(&mut buffer, str::to_bytes(~"GET / HTTP/1.1\r\nX-Name: "));
+	append_bytes(&mut buffer, ~[0xC3u8, 0x28u8, 0x0Du8, 0x0Au8, 0x0Du8, 0x0Au8]);
+	
+	// The headers are already buffered so the socket is never read.
+	let pipe = os::pipe();
+	let sock = @socket::socket::socket_handle(pipe.in);
+	let result = read_headers(&config, "1.2.3.4", sock, &mut buffer);
+	unsafe {libc::close(pipe.out);}
+	
+	match result
+	{
+		result::Err(move err) =>
+		{
+			match read_error_to_mesg(err)
+			{
+				ReadBadRequest(ref mesg) =>
+				{
+					let (header, body) = process_bad_request(&config, *mesg, "10.11.12.13", "1.2.3.4");
+					assert header.starts_with("HTTP/1.1 400 Bad Request\r\n");
+					assert body_len(&body) > 0u;
+				}
+				_ => fail ~"expected ReadBadRequest",
+			}
+		}
+		result::Ok(_) => fail ~"expected an error",
+	}
+}
+
+#[test]
+fn deadlines()
+{
+	// Zero disables the timeout.
+	assert make_deadline(0u) == 0u64;
+	
+	let before = std::time::precise_time_ns()/1_000_000u64;
+	let deadline = make_deadline(5000u);
+	let after = std::time::precise_time_ns()/1_000_000u64;
+	assert deadline >= before + 5000u64 && deadline <= after + 5000u64;
+	
+	let pipe = os::pipe();
+	let sock = @socket::socket::socket_handle(pipe.in);
+	assert wait_readable(sock, 0u64).get();						// no deadline so there's no need to wait
+	assert !wait_readable(sock, 1u64).get();					// deadline has already passed
+	assert !wait_readable(sock, make_deadline(10u)).get();		// nothing to read
+	
+	unsafe
+	{
+		do str::as_buf("x") |buffer, _len| {libc::write(pipe.out, buffer as *libc::c_void, 1u as libc::size_t)};
+		assert wait_readable(sock, make_deadline(1000u)).get();
+		libc::close(pipe.out);
+	}
+}
+
+#[test]
+fn connection_close()
+{
+	assert closes_connection(&headers_from_vector(~[(~"Connection", ~"close")]));
+	assert closes_connection(&headers_from_vector(~[(~"connection", ~"Close")]));
+	assert closes_connection(&headers_from_vector(~[(~"Connection", ~"foo, CLOSE")]));
+	
+	assert !closes_connection(&headers_from_vector(~[(~"Connection", ~"keep-alive")]));
+	assert !closes_connection(&headers_from_vector(~[(~"Connection", ~"closed")]));
+	assert !closes_connection(&headers_from_vector(~[(~"X-Connection", ~"close")]));
+}
+
+#[test]
+fn header_limits()
+{
+	let config = Config {max_header_bytes: 60u, max_header_count: 2u, max_url_length: 10u, ..initialize_config()};
+	
+	assert check_header_limits(&config, str::to_bytes(~"GET /012345678 HTTP/1.1\r\nHost: x\r\n\r\n")).is_ok();
+	
+	match check_header_limits(&config, str::to_bytes(~"GET /0123456789 HTTP/1.1\r\nHost: x\r\n\r\n"))
+	{
+		result::Err(UrlTooLong) => {}
+		result => fail fmt!("expected UrlTooLong but found %?", result),
+	}
+	
+	match check_header_limits(&config, str::to_bytes(~"GET / HTTP/1.1\r\nHost: x\r\nAccept: y\r\nAccept-Language: z\r\n\r\n"))
+	{
+		result::Err(HeadersTooLarge) => {}
+		result => fail fmt!("expected HeadersTooLarge but found %?", result),
+	}
+	
+	match check_header_limits(&config, str::to_bytes(~"GET / HTTP/1.1\r\nUser-Agent: Mozilla/5.0 (Macintosh; Intel Mac OS X 10.7)\r\n\r\n"))
+	{
+		result::Err(HeadersTooLarge) => {}
+		result => fail fmt!("expected HeadersTooLarge but found %?", result),
+	}
+}
+
+#[test]
+fn expect_continue()
+{
+	fn no_uploads(_config: &Config, route: &str, _headers: &Headers) -> bool
+	{
+		route != "upload"
+	}
+	
+	let config = Config {
+		routes: ~[Route(~"upload", ~"POST", ~"/upload/{name}"), Route(~"comment", ~"POST", ~"/comment")],
+		expect_continue: no_uploads,
+		..initialize_config()};
+	let parse = http_parser::make_parser();
+	let port = oldcomm::Port();
+	let chan = oldcomm::Chan(&port);
+	let buffer = ReadBuffer();
+	
+	let request = result::unwrap(parse(~"POST /comment HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 10\r\n\r\n"));
+	assert handle_expect(&config, &request, &buffer, chan);
+	assert port.peek();
+	match port.recv()
+	{
+		ReadContinue => {}
+		_ => fail ~"expected ReadContinue",
+	}
+	
+	let request = result::unwrap(parse(~"POST /upload/foo.png HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 10\r\n\r\n"));
+	assert !handle_expect(&config, &request, &buffer, chan);
+	
+	let request = result::unwrap(parse(~"POST /comment HTTP/1.1\r\nExpect: something-else\r\nContent-Length: 10\r\n\r\n"));
+	assert !handle_expect(&config, &request, &buffer, chan);
+	
+	let request = result::unwrap(parse(~"POST /comment HTTP/1.0\r\nExpect: 100-continue\r\nContent-Length: 10\r\n\r\n"));
+	assert handle_expect(&config, &request, &buffer, chan);
+	assert !port.peek();
+	
+	// HTTP/1.0 clients don't get a 100 response but expect_continue can still reject them.
+	let request = result::unwrap(parse(~"POST /upload/foo.png HTTP/1.0\r\nExpect: 100-continue\r\nContent-Length: 10\r\n\r\n"));
+	assert !handle_expect(&config, &request, &buffer, chan);
+	assert !port.peek();
+}
+
+#[test]
+fn last_chunk()
+{
+	assert utils::check_strs(make_last_chunk(&Headers()), ~"0\r\n\r\n");
+	
+	let trailers = headers_from_vector(~[(~"Content-MD5", ~"Q2hlY2sgSW50ZWdyaXR5IQ=="), (~"X-Rows", ~"42")]);
+	assert utils::check_strs(make_last_chunk(&trailers), ~"0\r\nContent-MD5: Q2hlY2sgSW50ZWdyaXR5IQ==\r\nX-Rows: 42\r\n\r\n");
+}
@@ -0,0 +1,372 @@
+//! HTTP headers for requests and responses.
+// See section 4.2 and 14 of http://www.w3.org/Protocols/rfc2616/rfc2616.html
+
+/// A list of HTTP headers.
+///
+/// Names are compared without regard to case (see section 4.2) and a header may appear
+/// more than once (e.g. Set-Cookie). Headers are written out in the order they were added.
+/// Note that this is a sendable type.
+pub struct Headers
+{
+	priv entries: ~[(~str, ~str)],
+}
+
+/// Returns an empty list of headers.
+pub fn Headers() -> Headers
+{
+	Headers {entries: ~[]}
+}
+
+/// Returns headers with the (name, value) entries.
+pub fn headers_from_vector(vector: &[(~str, ~str)]) -> Headers
+{
+	Headers {entries: vec::from_slice(vector)}
+}
+
+/// Returns true if the two header names are equivalent.
+pub fn same_name(a: &str, b: &str) -> bool
+{
+	a.len() == b.len() && str::to_lower(a) == str::to_lower(b)
+}
+
+impl Headers
+{
+	/// Returns the number of header lines.
+	fn len(&self) -> uint
+	{
+		vec::len(self.entries)
+	}
+
+	fn is_empty(&self) -> bool
+	{
+		vec::is_empty(self.entries)
+	}
+
+	fn contains_key(&self, name: &str) -> bool
+	{
+		for self.entries.each |entry|
+		{
+			let (ref n, _) = *entry;
+			if same_name(*n, name)
+			{
+				return true;
+			}
+		}
+		false
+	}
+
+	/// Returns the value of the named header. If the header appears more than once the
+	/// values are joined with commas (which section 4.2 says is equivalent).
+	fn find(&self, name: &str) -> option::Option<~str>
+	{
+		let values = self.find_all(name);
+		if vec::is_not_empty(values)
+		{
+			option::Some(str::connect(values, ~", "))
+		}
+		else
+		{
+			option::None
+		}
+	}
+
+	/// Like find except that this fails if the header is missing.
+	fn get(&self, name: &str) -> ~str
+	{
+		match self.find(name)
+		{
+			option::Some(move value) => value,
+			option::None => fail fmt!("missing %s header", name),
+		}
+	}
+
+	/// Returns the value of each line of the named header.
+	fn find_all(&self, name: &str) -> ~[~str]
+	{
+		let mut values = ~[];
+		for self.entries.each |entry|
+		{
+			let (ref n, ref v) = *entry;
+			if same_name(*n, name)
+			{
+				vec::push(&mut values, copy *v);
+			}
+		}
+		values
+	}
+
+	/// Returns the comma separated elements of the named header (e.g. the
+	/// directives of a Cache-Control header). Empty elements are skipped.
+	fn tokens(&self, name: &str) -> ~[~str]
+	{
+		let mut tokens = ~[];
+		for self.find_all(name).each |value|
+		{
+			for str::split_char(*value, ',').each |token|
+			{
+				let token = str::trim(*token);
+				if token.is_not_empty()
+				{
+					vec::push(&mut tokens, token);
+				}
+			}
+		}
+		tokens
+	}
+
+	/// Replaces any existing values for the header with value.
+	fn insert(&mut self, name: ~str, value: ~str)
+	{
+		self.remove(name);
+		vec::push(&mut self.entries, (name, value));
+	}
+
+	/// Adds a value for the header keeping any existing values.
+	fn add(&mut self, name: ~str, value: ~str)
+	{
+		vec::push(&mut self.entries, (name, value));
+	}
+
+	/// Removes all the values for the header. Returns true if there were any.
+	fn remove(&mut self, name: &str) -> bool
+	{
+		let old_len = vec::len(self.entries);
+		self.entries = do vec::filter(self.entries) |entry|
+		{
+			let (ref n, _) = *entry;
+			!same_name(*n, name)
+		};
+		vec::len(self.entries) < old_len
+	}
+
+	/// Calls blk with the name and value of each header line.
+	fn each(&self, blk: fn(&~str, &~str) -> bool)
+	{
+		for self.entries.each |entry|
+		{
+			let (ref n, ref v) = *entry;
+			if !blk(n, v)
+			{
+				break;
+			}
+		}
+	}
+
+	// ---- typed accessors -------------------------------------------------------------
+	/// Returns the media type of the Content-Type header without parameters, e.g. "text/html".
+	fn content_type(&self) -> option::Option<~str>
+	{
+		do self.find("Content-Type").map |value|
+		{
+			match str::find_char(*value, ';')
+			{
+				option::Some(i) => str::to_lower(str::trim(value.slice(0, i))),
+				option::None => str::to_lower(str::trim(*value)),
+			}
+		}
+	}
+
+	/// Returns the charset parameter of the Content-Type header.
+	fn charset(&self) -> option::Option<~str>
+	{
+		match self.find("Content-Type")
+		{
+			option::Some(ref value) => find_param(*value, "charset"),
+			option::None => option::None,
+		}
+	}
+
+	fn set_content_type(&mut self, value: ~str)
+	{
+		self.insert(~"Content-Type", value);
+	}
+
+	/// Returns None if the header is missing or malformed.
+	fn content_length(&self) -> option::Option<uint>
+	{
+		match self.find("Content-Length")
+		{
+			option::Some(ref value) => uint::from_str(str::trim(*value)),
+			option::None => option::None,
+		}
+	}
+
+	fn set_content_length(&mut self, len: uint)
+	{
+		self.insert(~"Content-Length", len.to_str());
+	}
+
+	/// Returns None if the header is missing or not an RFC 1123 date (see section 3.3.1).
+	fn date(&self) -> option::Option<std::time::Tm>
+	{
+		self.find_date("Date")
+	}
+
+	fn set_date(&mut self, date: &std::time::Tm)
+	{
+		self.insert(~"Date", date.to_utc().rfc822());
+	}
+
+	/// Returns the named header as a date, e.g. for Last-Modified or If-Modified-Since.
+	fn find_date(&self, name: &str) -> option::Option<std::time::Tm>
+	{
+		match self.find(name)
+		{
+			option::Some(ref value) =>
+			{
+				match std::time::strptime(str::trim(*value), "%a, %d %b %Y %H:%M:%S GMT")
+				{
+					result::Ok(move tm) => option::Some(tm),
+					result::Err(_) => option::None,
+				}
+			}
+			option::None =>
+			{
+				option::None
+			}
+		}
+	}
+
+	/// Returns the ETag including the quotes (and the W/ prefix for weak tags).
+	fn etag(&self) -> option::Option<~str>
+	{
+		self.find("ETag")
+	}
+
+	fn set_etag(&mut self, etag: ~str)
+	{
+		self.insert(~"ETag", etag);
+	}
+
+	/// Returns the directives of the Cache-Control header, e.g. ~[~"no-cache", ~"max-age=60"].
+	fn cache_control(&self) -> ~[~str]
+	{
+		self.tokens("Cache-Control")
+	}
+
+	fn set_cache_control(&mut self, value: ~str)
+	{
+		self.insert(~"Cache-Control", value);
+	}
+
+	/// Adds name to the Vary header if it isn't already there (see section 14.44).
+	fn add_vary(&mut self, name: &str)
+	{
+		if !self.tokens("Vary").any(|t| {same_name(*t, name)})
+		{
+			self.add(~"Vary", name.to_owned());
+		}
+	}
+
+	/// Returns the lower cased tokens of the Connection header, e.g. ~[~"keep-alive"].
+	fn connection(&self) -> ~[~str]
+	{
+		self.tokens("Connection").map(|t| {str::to_lower(*t)})
+	}
+
+	/// Returns the lower cased codings of the Transfer-Encoding header, e.g. ~[~"chunked"].
+	fn transfer_codings(&self) -> ~[~str]
+	{
+		self.tokens("Transfer-Encoding").map(|t| {str::to_lower(*t)})
+	}
+}
+
+// Returns the value of a parameter like the charset in "text/html; charset=UTF-8".
+priv fn find_param(value: &str, name: &str) -> option::Option<~str>
+{
+	for str::split_char(value, ';').eachi |i, param|
+	{
+		if i > 0u
+		{
+			match str::find_char(*param, '=')
+			{
+				option::Some(j) =>
+				{
+					if same_name(str::trim(param.slice(0, j)), name)
+					{
+						let v = str::trim(param.slice(j+1, param.len()));
+						return option::Some(str::trim_chars(v, ~['"']));
+					}
+				}
+				option::None =>
+				{
+				}
+			}
+		}
+	}
+	option::None
+}
+
+// ---- Unit Tests ------------------------------------------------------------
+#[test]
+fn case_insensitive()
+{
+	let mut headers = headers_from_vector(~[(~"Content-Type", ~"text/html"), (~"host", ~"localhost")]);
+
+	assert headers.contains_key("content-type");
+	assert headers.get("CONTENT-TYPE") == ~"text/html";
+	assert headers.get("Host") == ~"localhost";
+	assert headers.find("Accept").is_none();
+
+	headers.insert(~"HOST", ~"example.com");
+	assert headers.len() == 2u;
+	assert headers.get("host") == ~"example.com";
+
+	assert headers.remove("Content-type");
+	assert !headers.remove("Content-type");
+	assert headers.len() == 1u;
+}
+
+#[test]
+fn multiple_values()
+{
+	let mut headers = Headers();
+	headers.add(~"Accept", ~"text/html");
+	headers.add(~"accept", ~"application/xml;q=0.9, */*;q=0.8");
+	headers.add(~"Set-Cookie", ~"a=1");
+	headers.add(~"Set-Cookie", ~"b=2");
+
+	assert utils::check_strs(headers.get("Accept"), ~"text/html, application/xml;q=0.9, */*;q=0.8");
+	assert utils::check_vectors(headers.tokens("Accept"), ~[~"text/html", ~"application/xml;q=0.9", ~"*/*;q=0.8"]);
+	assert utils::check_vectors(headers.find_all("set-cookie"), ~[~"a=1", ~"b=2"]);
+
+	let mut lines = ~[];
+	for headers.each |name, value| {vec::push(&mut lines, fmt!("%s: %s", *name, *value));}
+	assert utils::check_vectors(lines, ~[~"Accept: text/html", ~"accept: application/xml;q=0.9, */*;q=0.8", ~"Set-Cookie: a=1", ~"Set-Cookie: b=2"]);
+}
+
+#[test]
+fn typed_headers()
+{
+	let mut headers = headers_from_vector(~[
+		(~"Content-Type", ~"Text/HTML; charset=\"UTF-8\""),
+		(~"Content-Length", ~" 42"),
+		(~"Date", ~"Tue, 15 Nov 1994 08:12:31 GMT"),
+		(~"Cache-Control", ~"no-cache, max-age=60"),
+		(~"Connection", ~"Keep-Alive"),
+		(~"Transfer-Encoding", ~"gzip, Chunked")]);
+
+	assert headers.content_type() == option::Some(~"text/html");
+	assert headers.charset() == option::Some(~"UTF-8");
+	assert headers.content_length() == option::Some(42u);
+	assert utils::check_vectors(headers.cache_control(), ~[~"no-cache", ~"max-age=60"]);
+	assert utils::check_vectors(headers.connection(), ~[~"keep-alive"]);
+	assert utils::check_vectors(headers.transfer_codings(), ~[~"gzip", ~"chunked"]);
+
+	let date = option::get(headers.date());
+	assert date.tm_year == 94_i32 && date.tm_mon == 10_i32 && date.tm_mday == 15_i32;
+	assert date.tm_hour == 8_i32 && date.tm_min == 12_i32 && date.tm_sec == 31_i32;
+
+	headers.add_vary("Accept");
+	headers.add_vary("Accept-Encoding");
+	headers.add_vary("accept");
+	assert utils::check_vectors(headers.find_all("Vary"), ~[~"Accept", ~"Accept-Encoding"]);
+
+	headers.set_content_length(10u);
+	headers.set_etag(~"\"abc\"");
+	assert headers.get("content-length") == ~"10";
+	assert headers.etag() == option::Some(~"\"abc\"");
+
+	headers.insert(~"Date", ~"yesterday");
+	assert headers.date().is_none();
+}
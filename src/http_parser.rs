@@ -1,4 +1,3 @@
-use core::send_map::linear::{LinearMap};
 use io::{WriterUtil};
 
 //use rparse::rparse::*;
@@ -16,8 +15,8 @@ pub struct HttpRequest
 	pub major_version: int,
 	pub minor_version: int,
 	pub url: ~str,
-	pub headers: LinearMap<~str, ~str>,		// these are not case sensitive so we lower case them
-	pub body: ~str,							// set elsewhere
+	pub headers: Headers,						// names are as sent by the client, lookups are case insensitive
+	pub body: ~[u8],							// set elsewhere
 }
 
 // We return a closure so that we can build the parser just once.
@@ -124,7 +123,7 @@ priv fn request_parser() -> Parser<HttpRequest>
 	// headers := header*
 	let name = match1({|c: char| c != ':'});
 	let header = do seq5(name, ":".lit(), value, continuation.r0(), crnl)
-		|n, _a2, v, cnt, _a5| {result::Ok((str::to_lower(*n), str::trim(*v) + str::connect(*cnt, ~"")))};	// 4.2 says that header names are case-insensitive so we lower case them
+		|n, _a2, v, cnt, _a5| {result::Ok((copy *n, str::trim(*v) + str::connect(*cnt, ~"")))};
 	let headers = header.r0();
 	
 	// request := method headers crnl
@@ -132,7 +131,7 @@ priv fn request_parser() -> Parser<HttpRequest>
 		|a1, h, _a2|
 		{
 			let (n, u, (v1, v2)) = a1;
-			result::Ok(HttpRequest {method: copy *n, major_version: v1, minor_version: v2, url: decode(*u), headers: utils::linear_map_from_vector(*h), body: ~""})};
+			result::Ok(HttpRequest {method: copy *n, major_version: v1, minor_version: v2, url: decode(*u), headers: headers_from_vector(*h), body: ~[]})};
 	
 	return request;
 }
@@ -195,14 +194,14 @@ fn test_get_method2()
 			assert equal(value.major_version, 1);
 			assert equal(value.minor_version, 1);
 			assert equal_strs(value.url, ~"/");
-//			assert equal(value.headers.len(), 6u);		// TODO: enable once https://github.com/mozilla/rust/issues/4277 is fixed
+			assert equal(value.headers.len(), 6u);
 			
-			assert equal_strs(value.headers.get(&~"host"), ~"localhost:8080");
-			assert equal_strs(value.headers.get(&~"user-agent"), ~"Mozilla/5.0 (Macintosh; Intel Mac OS X 10.7; rv:11.0) Gecko/20100101 Firefox/11.0");
-			assert equal_strs(value.headers.get(&~"accept"), ~"text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8");
-			assert equal_strs(value.headers.get(&~"accept-language"), ~"en-us,en;q=0.5");
-			assert equal_strs(value.headers.get(&~"accept-encoding"), ~"gzip, deflate");
-			assert equal_strs(value.headers.get(&~"connection"), ~"keep-alive");
+			assert equal_strs(value.headers.get("host"), ~"localhost:8080");
+			assert equal_strs(value.headers.get("user-agent"), ~"Mozilla/5.0 (Macintosh; Intel Mac OS X 10.7; rv:11.0) Gecko/20100101 Firefox/11.0");
+			assert equal_strs(value.headers.get("accept"), ~"text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8");
+			assert equal_strs(value.headers.get("accept-language"), ~"en-us,en;q=0.5");
+			assert equal_strs(value.headers.get("accept-encoding"), ~"gzip, deflate");
+			assert equal_strs(value.headers.get("connection"), ~"keep-alive");
 		}
 		result::Err(ref mesg) =>
 		{
@@ -240,9 +239,30 @@ fn test_header_values()
 	{
 		result::Ok(ref value) =>
 		{
-			assert equal_strs(value.headers.get(&~"host"), ~"xxx");
-			assert equal_strs(value.headers.get(&~"blah"), ~"bbb");
-			assert equal_strs(value.headers.get(&~"multi"), ~"line1 line2 line3");
+			assert equal_strs(value.headers.get("host"), ~"xxx");
+			assert equal_strs(value.headers.get("blah"), ~"bbb");
+			assert equal_strs(value.headers.get("multi"), ~"line1 line2 line3");
+		}
+		result::Err(ref mesg) =>
+		{
+			io::stderr().write_line(*mesg);
+			assert false;
+		}
+	}
+}
+
+#[test]
+fn test_repeated_headers()
+{
+	let p = make_parser();
+
+	match p(~"GET / HTTP/1.1\r\nAccept: text/html\r\nHost: xxx\r\naccept: text/plain;q=0.5\r\n\r\n")
+	{
+		result::Ok(ref value) =>
+		{
+			assert equal(value.headers.len(), 3u);
+			assert equal_strs(value.headers.get("ACCEPT"), ~"text/html, text/plain;q=0.5");
+			assert equal(value.headers.find_all("Accept").len(), 2u);
 		}
 		result::Err(ref mesg) =>
 		{
@@ -0,0 +1,370 @@
+//! Content negotiation using the quality values sent by clients.
+// See sections 3.9, 12, and 14.1 of http://www.w3.org/Protocols/rfc2616/rfc2616.html
+
+/// An element of an Accept header, e.g. "text/html;level=1;q=0.8".
+///
+/// * type_ and subtype are lower cased and may be "*".
+/// * params are the media type parameters (accept-extensions following q are dropped).
+/// * quality is in [0.0, 1.0] and defaults to 1.0. Zero means the type is not acceptable.
+pub struct MediaRange
+{
+	pub type_: ~str,
+	pub subtype: ~str,
+	pub params: ~[(~str, ~str)],
+	pub quality: float,
+}
+
+/// Parses the value of an Accept header. Malformed media ranges are skipped.
+pub fn parse_accept(value: &str) -> ~[MediaRange]
+{
+	let mut ranges = ~[];
+
+	for str::split_char(value, ',').each |element|
+	{
+		match parse_element(*element)
+		{
+			option::Some((ref media, ref params, quality)) =>
+			{
+				match str::find_char(*media, '/')
+				{
+					option::Some(i) if i > 0u && i + 1u < media.len() =>
+					{
+						let type_ = media.slice(0, i);
+						let subtype = media.slice(i + 1u, media.len());
+						if type_ != ~"*" || subtype == ~"*"
+						{
+							vec::push(&mut ranges, MediaRange {type_: type_, subtype: subtype, params: copy *params, quality: quality});
+						}
+						else
+						{
+							info!("ignoring media range '%s'", *element);
+						}
+					}
+					_ =>
+					{
+						info!("ignoring media range '%s'", *element);
+					}
+				}
+			}
+			option::None =>
+			{
+				info!("ignoring media range '%s'", *element);
+			}
+		}
+	}
+
+	ranges
+}
+
+/// Returns the quality the client assigned to mime_type (which may include parameters,
+/// e.g. "text/html; charset=UTF-8"). Section 14.1 says that the most specific matching
+/// range is used so "text/html;q=0.5, */*" gives text/html a quality of 0.5. Returns
+/// zero if mime_type is not acceptable.
+pub fn media_quality(ranges: &[MediaRange], mime_type: &str) -> float
+{
+	let (media, params) = match parse_element(mime_type)
+	{
+		option::Some((move media, move params, _)) => (media, params),
+		option::None => (str::to_lower(mime_type), ~[]),
+	};
+	let (type_, subtype) = match str::find_char(media, '/')
+	{
+		option::Some(i) => (media.slice(0, i), media.slice(i + 1u, media.len())),
+		option::None => (copy media, ~""),
+	};
+
+	let mut quality = 0.0;
+	let mut precedence = 0u;
+	for ranges.each |range|
+	{
+		let p = if range.type_ == ~"*"
+		{
+			1u
+		}
+		else if range.type_ != type_
+		{
+			0u
+		}
+		else if range.subtype == ~"*"
+		{
+			2u
+		}
+		else if range.subtype != subtype
+		{
+			0u
+		}
+		else if params_match(range.params, params)
+		{
+			3u + range.params.len()
+		}
+		else
+		{
+			0u
+		};
+
+		if p > precedence
+		{
+			precedence = p;
+			quality = range.quality;
+		}
+	}
+
+	quality
+}
+
+/// Returns the mime type with the highest quality along with its quality. If there
+/// are ties the first type wins.
+pub fn best_type(ranges: &[MediaRange], mime_types: &[~str]) -> (float, ~str)
+{
+	let mut quality = 0.0;
+	let mut best = ~"";
+	for mime_types.each |mime_type|
+	{
+		let q = media_quality(ranges, *mime_type);
+		if q > quality
+		{
+			quality = q;
+			best = copy *mime_type;
+		}
+	}
+	(quality, best)
+}
+
+/// Returns true if one of the ranges explicitly names mime_type (i.e. without
+/// wildcards) with a non-zero quality.
+pub fn accepts_explicitly(ranges: &[MediaRange], mime_type: &str) -> bool
+{
+	let mime_type = str::to_lower(mime_type);
+	do ranges.any |range|
+	{
+		range.quality > 0.0 && fmt!("%s/%s", range.type_, range.subtype) == mime_type
+	}
+}
+
+/// Parses a header like Accept-Language or Accept-Encoding into lower cased
+/// values and their qualities, e.g. ~[(~"en-us", 1.0), (~"en", 0.5)].
+pub fn parse_qvalues(value: &str) -> ~[(~str, float)]
+{
+	let mut values = ~[];
+	for str::split_char(value, ',').each |element|
+	{
+		match parse_element(*element)
+		{
+			option::Some((move v, _, quality)) => vec::push(&mut values, (v, quality)),
+			option::None => info!("ignoring '%s'", *element),
+		}
+	}
+	values
+}
+
+/// Returns the quality the client assigned to a language tag like "en-gb". Section 14.4
+/// says that a range matches a tag if it is the tag or a prefix of the tag followed
+/// by "-". The longest matching range is used and "*" matches any tag.
+pub fn language_quality(ranges: &[(~str, float)], tag: &str) -> float
+{
+	let tag = str::to_lower(tag);
+	let mut quality = 0.0;
+	let mut precedence = 0u;
+	for ranges.each |entry|
+	{
+		let (ref range, q) = *entry;
+		let p = if *range == ~"*"
+		{
+			1u
+		}
+		else if *range == tag || tag.starts_with(*range + ~"-")
+		{
+			1u + range.len()
+		}
+		else
+		{
+			0u
+		};
+
+		if p > precedence
+		{
+			precedence = p;
+			quality = q;
+		}
+	}
+	quality
+}
+
+/// Returns the languages with a non-zero quality ordered by decreasing quality
+/// (ties are kept in their original order).
+pub fn preferred_languages(ranges: &[(~str, float)], languages: &[~str]) -> ~[~str]
+{
+	let mut entries = ~[];
+	for languages.each |language|
+	{
+		let q = language_quality(ranges, *language);
+		if q > 0.0
+		{
+			vec::push(&mut entries, (q, copy *language));
+		}
+	}
+
+	pure fn ge(a: &(float, ~str), b: &(float, ~str)) -> bool {a.first() >= b.first()}
+	do std::sort::merge_sort(entries, ge).map |entry| {entry.second()}
+}
+
+/// Returns the quality the client assigned to a content coding like "gzip" using
+/// the values from an Accept-Encoding header (see section 14.3). Section 3.5 says
+/// that "x-gzip" is equivalent to "gzip".
+pub fn coding_quality(ranges: &[(~str, float)], coding: &str) -> float
+{
+	let mut quality = 0.0;
+	for ranges.each |entry|
+	{
+		let (ref range, q) = *entry;
+		if *range == coding.to_owned() || *range == ~"x-" + coding
+		{
+			return q;
+		}
+		else if *range == ~"*"
+		{
+			quality = q;
+		}
+	}
+	quality
+}
+
+// Each range parameter must be present in params (section 14.1 says that
+// "text/html;level=1" only matches text/html with a level of 1).
+priv fn params_match(range_params: &[(~str, ~str)], params: &[(~str, ~str)]) -> bool
+{
+	do range_params.all |rp|
+	{
+		params.contains(rp)
+	}
+}
+
+// Splits an element like "text/html;level=1;q=0.5" into its lower cased value, its
+// parameters, and its quality. Returns None if the element is empty or the
+// quality is malformed.
+priv fn parse_element(element: &str) -> option::Option<(~str, ~[(~str, ~str)], float)>
+{
+	let parts = str::split_char(element, ';');
+	let value = str::to_lower(str::trim(parts[0]));
+	if value.is_empty()
+	{
+		return option::None;
+	}
+
+	let mut params = ~[];
+	let mut quality = 1.0;
+	for vec::view(parts, 1u, parts.len()).each |part|
+	{
+		let (name, v) = match str::find_char(*part, '=')
+		{
+			option::Some(i) => (str::to_lower(str::trim(part.slice(0, i))), str::trim(part.slice(i + 1u, part.len()))),
+			option::None => (str::to_lower(str::trim(*part)), ~""),
+		};
+
+		if name == ~"q"
+		{
+			match parse_qvalue(v)
+			{
+				option::Some(q) => quality = q,
+				option::None => return option::None,
+			}
+			break;								// anything after q is an accept-extension
+		}
+		else if name.is_not_empty()
+		{
+			vec::push(&mut params, (name, str::to_lower(str::trim_chars(v, ~['"']))));
+		}
+	}
+
+	option::Some((value, params, quality))
+}
+
+// qvalue := ("0" ["." 0*3DIGIT]) | ("1" ["." 0*3("0")])
+priv fn parse_qvalue(text: &str) -> option::Option<float>
+{
+	if text.is_empty() || text.len() > 5u || !str::all(text, |c| {char::is_digit(c) || c == '.'})
+	{
+		return option::None;
+	}
+
+	match float::from_str(text)
+	{
+		option::Some(q) if q >= 0.0 && q <= 1.0 => option::Some(q),
+		_ => option::None,
+	}
+}
+
+// ---- Unit Tests ------------------------------------------------------------
+#[test]
+fn parse_ranges()
+{
+	let ranges = parse_accept("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8");
+	assert ranges.len() == 4u;
+	assert ranges[0].type_ == ~"text" && ranges[0].subtype == ~"html" && ranges[0].quality == 1.0;
+	assert ranges[2].subtype == ~"xml" && ranges[2].quality == 0.9;
+	assert ranges[3].type_ == ~"*" && ranges[3].quality == 0.8;
+
+	let ranges = parse_accept("Text/HTML; Level=1; q=0.5; ext=foo, text/*;q=2, */html, , image/png;q=0");
+	assert ranges.len() == 2u;
+	assert ranges[0].type_ == ~"text" && ranges[0].subtype == ~"html" && ranges[0].quality == 0.5;
+	assert ranges[0].params == ~[(~"level", ~"1")];
+	assert ranges[1].type_ == ~"image" && ranges[1].quality == 0.0;
+}
+
+#[test]
+fn quality_of_types()
+{
+	// This is the example from section 14.1.
+	let ranges = parse_accept("text/*;q=0.3, text/html;q=0.7, text/html;level=1, text/html;level=2;q=0.4, */*;q=0.5");
+	assert media_quality(ranges, "text/html;level=1") == 1.0;
+	assert media_quality(ranges, "text/html") == 0.7;
+	assert media_quality(ranges, "text/plain") == 0.3;
+	assert media_quality(ranges, "image/jpeg") == 0.5;
+	assert media_quality(ranges, "text/html;level=2") == 0.4;
+	assert media_quality(ranges, "text/html;level=3") == 0.7;
+	assert media_quality(ranges, "text/html; charset=UTF-8") == 0.7;
+
+	let ranges = parse_accept("image/*, image/gif;q=0");
+	assert media_quality(ranges, "image/png") == 1.0;
+	assert media_quality(ranges, "image/gif") == 0.0;
+	assert media_quality(ranges, "text/html") == 0.0;
+
+	let ranges = parse_accept("application/json;q=0.9, text/*;q=0.9");
+	assert best_type(ranges, ~[~"text/html", ~"application/json"]) == (0.9, ~"text/html");
+	assert best_type(ranges, ~[~"text/csv", ~"image/png"]) == (0.9, ~"text/csv");
+	assert best_type(ranges, ~[~"image/png"]) == (0.0, ~"");
+	
+	assert accepts_explicitly(parse_accept("text/event-stream"), "text/event-stream");
+	assert !accepts_explicitly(parse_accept("text/*, */*"), "text/event-stream");
+}
+
+#[test]
+fn language_ranges()
+{
+	let ranges = parse_qvalues("da, en-gb;q=0.8, en;q=0.7");
+	assert ranges == ~[(~"da", 1.0), (~"en-gb", 0.8), (~"en", 0.7)];
+	assert language_quality(ranges, "da") == 1.0;
+	assert language_quality(ranges, "en-GB") == 0.8;
+	assert language_quality(ranges, "en-us") == 0.7;
+	assert language_quality(ranges, "en") == 0.7;
+	assert language_quality(ranges, "fr") == 0.0;
+	
+	let ranges = parse_qvalues("fr-ca, *;q=0.1, de;q=0");
+	assert language_quality(ranges, "fr") == 0.1;
+	assert language_quality(ranges, "de") == 0.0;
+	
+	let ranges = parse_qvalues("fr;q=0.8, en;q=0.5, es;q=0.8");
+	assert preferred_languages(ranges, ~[~"en", ~"es", ~"de", ~"fr"]) == ~[~"es", ~"fr", ~"en"];
+}
+
+#[test]
+fn coding_ranges()
+{
+	let ranges = parse_qvalues("gzip;q=1.0, identity; q=0.5, *;q=0");
+	assert coding_quality(ranges, "gzip") == 1.0;
+	assert coding_quality(ranges, "deflate") == 0.0;
+	
+	let ranges = parse_qvalues("x-gzip;q=0.5, *");
+	assert coding_quality(ranges, "gzip") == 0.5;
+	assert coding_quality(ranges, "deflate") == 1.0;
+}
@@ -0,0 +1,147 @@
+//! Bindings for the handful of socket and file calls that rust-socket and core don't expose.
+
+pub const SHUT_RD: libc::c_int = 0i32;
+pub const SHUT_WR: libc::c_int = 1i32;
+pub const SHUT_RDWR: libc::c_int = 2i32;
+
+pub const POLLIN: i16 = 0x0001i16;
+pub const POLLOUT: i16 = 0x0004i16;
+
+const EINTR: libc::c_int = 4i32;
+
+#[cfg(target_os = "linux")]
+const EAGAIN: libc::c_int = 11i32;
+#[cfg(target_os = "macos")]
+const EAGAIN: libc::c_int = 35i32;
+
+#[cfg(target_os = "linux")]
+const MSG_DONTWAIT: libc::c_int = 0x40i32;
+#[cfg(target_os = "macos")]
+const MSG_DONTWAIT: libc::c_int = 0x80i32;
+
+struct PollFd
+{
+	fd: libc::c_int,
+	events: i16,
+	revents: i16,
+}
+
+#[nolink]
+extern mod c
+{
+	fn shutdown(socket: libc::c_int, how: libc::c_int) -> libc::c_int;
+	fn poll(fds: *PollFd, nfds: libc::c_uint, timeout: libc::c_int) -> libc::c_int;
+	fn stat(path: *libc::c_char, buf: *mut libc::stat) -> libc::c_int;
+	fn send(socket: libc::c_int, buffer: *libc::c_void, len: libc::size_t, flags: libc::c_int) -> libc::ssize_t;
+}
+
+#[cfg(target_os = "linux")]
+#[nolink]
+extern mod errno_c
+{
+	fn __errno_location() -> *libc::c_int;
+}
+
+#[cfg(target_os = "macos")]
+#[nolink]
+extern mod errno_c
+{
+	fn __error() -> *libc::c_int;
+}
+
+#[cfg(target_os = "linux")]
+priv fn errno() -> libc::c_int
+{
+	unsafe {*errno_c::__errno_location()}
+}
+
+#[cfg(target_os = "macos")]
+priv fn errno() -> libc::c_int
+{
+	unsafe {*errno_c::__error()}
+}
+
+// Disables reads and/or writes on a socket without closing the descriptor. Any
+// task blocked in recv or accept on the socket will return with an error (or EOF).
+pub fn shutdown(fd: libc::c_int, how: libc::c_int) -> bool
+{
+	unsafe {c::shutdown(fd, how) == 0i32}
+}
+
+// Blocks until fd is ready for the events (POLLIN or POLLOUT) or timeout ms elapse
+// (zero means wait forever). Returns false iff the timeout elapsed. Errors and hangups
+// on fd return true so that the caller's recv or send will report them.
+pub fn wait_for(fd: libc::c_int, events: i16, timeout: uint) -> result::Result<bool, ~str>
+{
+	let deadline = std::time::precise_time_ns()/1_000_000u64 + timeout as u64;
+	let pfd = PollFd {fd: fd, events: events, revents: 0i16};
+	loop
+	{
+		let ms = if timeout > 0u
+		{
+			let now = std::time::precise_time_ns()/1_000_000u64;
+			if now >= deadline
+			{
+				return result::Ok(false);
+			}
+			(deadline - now) as libc::c_int
+		}
+		else
+		{
+			-1i32 as libc::c_int
+		};
+		
+		let result = unsafe {c::poll(ptr::addr_of(&pfd), 1u32 as libc::c_uint, ms)};
+		if result >= 0i32
+		{
+			return result::Ok(result != 0i32);
+		}
+		else if errno() != EINTR
+		{
+			return result::Err(os::last_os_error());
+		}
+	}
+}
+
+// Writes as much of buffer to the socket as it will accept without blocking. Returns
+// the number of bytes written which will be zero if the socket's send buffer is full.
+pub fn send_some(fd: libc::c_int, buffer: *u8, len: uint) -> result::Result<uint, ~str>
+{
+	loop
+	{
+		let result = unsafe {c::send(fd, buffer as *libc::c_void, len as libc::size_t, MSG_DONTWAIT)};
+		if result >= 0
+		{
+			return result::Ok(result as uint);
+		}
+		
+		let err = errno();
+		if err == EAGAIN
+		{
+			return result::Ok(0u);
+		}
+		else if err != EINTR
+		{
+			return result::Err(os::last_os_error());
+		}
+	}
+}
+
+// Returns the size and modification time (in seconds since 1970) of the file at path.
+pub fn file_info(path: &Path) -> option::Option<(uint, i64)>
+{
+	unsafe
+	{
+		let buffer = vec::from_elem(sys::size_of::<libc::stat>(), 0u8);
+		let st: *mut libc::stat = cast::reinterpret_cast(&vec::raw::to_ptr(buffer));
+		let result = do str::as_c_str(path.to_str()) |p| {c::stat(p, st)};
+		if result == 0i32
+		{
+			option::Some(((*st).st_size as uint, (*st).st_mtime as i64))
+		}
+		else
+		{
+			option::None
+		}
+	}
+}
@@ -0,0 +1,246 @@
+//! Byte range requests for static files.
+// See sections 14.16, 14.27, 14.35, and 19.2 of http://www.w3.org/Protocols/rfc2616/rfc2616.html
+use rand::{RngUtil};
+
+// Clients can ask for the same bytes over and over so we limit the number of parts
+// in a multipart/byteranges response (ranges are merged before this is checked).
+priv const MAX_RANGES: uint = 16u;
+
+/// Parses a Range header value like "bytes=0-499, -500" for an entity with len bytes.
+/// Returns the inclusive (first, last) byte positions of the satisfiable ranges. Returns
+/// None if the header is malformed or doesn't use byte units (in which case section
+/// 14.35.1 says that the header should be ignored).
+pub fn parse_ranges(value: &str, len: uint) -> option::Option<~[(uint, uint)]>
+{
+	let value = str::trim(value);
+	if !value.starts_with("bytes=")
+	{
+		return option::None;
+	}
+
+	let mut ranges = ~[];
+	let mut count = 0u;
+	for str::split_char(value.slice(6, value.len()), ',').each |spec|
+	{
+		let spec = str::trim(*spec);
+		if spec.is_not_empty()
+		{
+			count += 1u;
+			match str::find_char(spec, '-')
+			{
+				option::Some(0u) =>
+				{
+					// suffix-byte-range-spec: the last n bytes
+					match uint::from_str(spec.slice(1, spec.len()))
+					{
+						option::Some(n) if n > 0u && len > 0u => vec::push(&mut ranges, (len - uint::min(n, len), len - 1u)),
+						option::Some(_) => {}
+						option::None => return option::None,
+					}
+				}
+				option::Some(i) =>
+				{
+					let first = uint::from_str(spec.slice(0, i));
+					let last = if i + 1u == spec.len() {option::Some(uint::max_value)} else {uint::from_str(spec.slice(i + 1u, spec.len()))};
+					if first.is_none() || last.is_none() || last.get() < first.get()
+					{
+						return option::None;
+					}
+					if first.get() < len
+					{
+						vec::push(&mut ranges, (first.get(), uint::min(last.get(), len - 1u)));
+					}
+				}
+				option::None =>
+				{
+					return option::None;
+				}
+			}
+		}
+	}
+
+	if count > 0u {option::Some(ranges)} else {option::None}
+}
+
+/// Adds Accept-Ranges to a response for a static file and, if the client asked for part
+/// of the file, returns 206 Partial Content (or 416 if none of the ranges can be satisfied).
+/// len is the size of the file and slice returns a body for the len bytes at offset.
+pub fn range_response(request: &Request, response: Response, len: uint, slice: fn(offset: uint, len: uint) -> Body) -> Response
+{
+	let mut response = response;
+	response.headers.insert(~"Accept-Ranges", ~"bytes");
+
+	let ranges = match request.headers.find("Range")
+	{
+		option::Some(ref value) if request.method == ~"GET" && if_range_matches(request, &response.headers) =>
+		{
+			usable_ranges(*value, len)
+		}
+		_ =>
+		{
+			option::None
+		}
+	};
+
+	match ranges
+	{
+		option::None =>
+		{
+			Response {body: slice(0u, len), ..response}
+		}
+		option::Some(ref ranges) if ranges.is_empty() =>
+		{
+			info!("responding with 416 Requested Range Not Satisfiable");
+			response.headers.insert(~"Content-Range", fmt!("bytes */%?", len));
+			Response {status: status::RequestedRangeNotSatisfiable, body: StringBody(@~""), ..response}
+		}
+		option::Some(ref ranges) if ranges.len() == 1u =>
+		{
+			let (first, last) = ranges[0];
+			response.headers.insert(~"Content-Range", fmt!("bytes %?-%?/%?", first, last, len));
+			Response {status: status::PartialContent, body: slice(first, last - first + 1u), ..response}
+		}
+		option::Some(ref ranges) =>
+		{
+			// Section 19.2 describes the multipart/byteranges type.
+			let boundary = fmt!("%016x", rand::Rng().gen_u64() as uint);
+			let mime_type = response.headers.find("Content-Type").get_default(~"application/octet-stream");
+
+			let mut parts = ~[];
+			for ranges.each |range|
+			{
+				let (first, last) = *range;
+				let header = fmt!("--%s\r\nContent-Type: %s\r\nContent-Range: bytes %?-%?/%?\r\n\r\n", boundary, mime_type, first, last, len);
+				vec::push(&mut parts, @StringBody(@header));
+				vec::push(&mut parts, @slice(first, last - first + 1u));
+				vec::push(&mut parts, @StringBody(@~"\r\n"));
+			}
+			vec::push(&mut parts, @StringBody(@fmt!("--%s--\r\n", boundary)));
+
+			response.headers.insert(~"Content-Type", ~"multipart/byteranges; boundary=" + boundary);
+			Response {status: status::PartialContent, body: CompoundBody(at_vec::from_owned(parts)), ..response}
+		}
+	}
+}
+
+// Like parse_ranges except that the ranges are merged and None is returned if there
+// are too many of them (so that the Range header is ignored).
+priv fn usable_ranges(value: &str, len: uint) -> option::Option<~[(uint, uint)]>
+{
+	match parse_ranges(value, len)
+	{
+		option::Some(ref ranges) =>
+		{
+			let ranges = coalesce_ranges(*ranges);
+			if ranges.len() > MAX_RANGES
+			{
+				info!("ignoring Range with %? ranges", ranges.len());
+				option::None
+			}
+			else
+			{
+				option::Some(ranges)
+			}
+		}
+		option::None =>
+		{
+			option::None
+		}
+	}
+}
+
+// Sorts the ranges and merges those that overlap or are adjacent (section 19.2 says
+// that the parts of a multipart/byteranges response shouldn't overlap).
+priv fn coalesce_ranges(ranges: &[(uint, uint)]) -> ~[(uint, uint)]
+{
+	pure fn le(a: &(uint, uint), b: &(uint, uint)) -> bool {a.first() <= b.first()}
+	
+	let mut result = ~[];
+	for std::sort::merge_sort(ranges, le).each |range|
+	{
+		let (first, last) = *range;
+		if result.is_not_empty()
+		{
+			let (prev_first, prev_last) = result[result.len() - 1u];
+			if first <= prev_last + 1u
+			{
+				result[result.len() - 1u] = (prev_first, uint::max(prev_last, last));
+				loop;
+			}
+		}
+		vec::push(&mut result, (first, last));
+	}
+	result
+}
+
+// Section 14.27 says that the ranges are only used if the entity is unchanged. Entity
+// tags must use the strong comparison function and dates must match exactly.
+priv fn if_range_matches(request: &Request, headers: &Headers) -> bool
+{
+	match request.headers.find("If-Range")
+	{
+		option::Some(ref value) =>
+		{
+			let value = str::trim(*value);
+			if value.starts_with("\"") || value.starts_with("W/")
+			{
+				!value.starts_with("W/") && headers.etag() == option::Some(value)
+			}
+			else
+			{
+				headers.find("Last-Modified") == option::Some(value)
+			}
+		}
+		option::None =>
+		{
+			true
+		}
+	}
+}
+
+// ---- Unit Tests ------------------------------------------------------------
+#[test]
+fn byte_ranges()
+{
+	// These are the examples from section 14.35.1.
+	assert parse_ranges("bytes=0-499", 10000u) == option::Some(~[(0u, 499u)]);
+	assert parse_ranges("bytes=500-999", 10000u) == option::Some(~[(500u, 999u)]);
+	assert parse_ranges("bytes=-500", 10000u) == option::Some(~[(9500u, 9999u)]);
+	assert parse_ranges("bytes=9500-", 10000u) == option::Some(~[(9500u, 9999u)]);
+	assert parse_ranges("bytes=0-0,-1", 10000u) == option::Some(~[(0u, 0u), (9999u, 9999u)]);
+	assert parse_ranges("bytes=500-600, 601-999", 10000u) == option::Some(~[(500u, 600u), (601u, 999u)]);
+
+	assert parse_ranges("bytes=9000-20000, -20000", 10000u) == option::Some(~[(9000u, 9999u), (0u, 9999u)]);
+	assert parse_ranges("bytes=10000-, -0", 10000u) == option::Some(~[]);
+
+	assert parse_ranges("bytes=500-100", 10000u).is_none();
+	assert parse_ranges("bytes=x-100", 10000u).is_none();
+	assert parse_ranges("bytes=", 10000u).is_none();
+	assert parse_ranges("pages=1-2", 10000u).is_none();
+}
+
+#[test]
+fn merged_ranges()
+{
+	assert coalesce_ranges(~[]) == ~[];
+	assert coalesce_ranges(~[(0u, 0u), (9999u, 9999u)]) == ~[(0u, 0u), (9999u, 9999u)];
+	assert coalesce_ranges(~[(9999u, 9999u), (0u, 0u)]) == ~[(0u, 0u), (9999u, 9999u)];
+	assert coalesce_ranges(~[(500u, 600u), (601u, 999u)]) == ~[(500u, 999u)];
+	assert coalesce_ranges(~[(9000u, 9999u), (0u, 9999u)]) == ~[(0u, 9999u)];
+	assert coalesce_ranges(~[(0u, 10u), (2u, 3u), (5u, 20u), (30u, 40u)]) == ~[(0u, 20u), (30u, 40u)];
+}
+
+#[test]
+fn too_many_ranges()
+{
+	let mut specs = ~[];
+	for uint::range(0u, MAX_RANGES) |i| {vec::push(&mut specs, fmt!("%?-%?", 10u*i, 10u*i + 5u))}
+	assert usable_ranges(~"bytes=" + str::connect(specs, ","), 10000u).get().len() == MAX_RANGES;
+	
+	// Ranges that merge don't count against the limit.
+	vec::push(&mut specs, ~"0-1");
+	assert usable_ranges(~"bytes=" + str::connect(specs, ","), 10000u).get().len() == MAX_RANGES;
+	
+	vec::push(&mut specs, ~"5000-6000");
+	assert usable_ranges(~"bytes=" + str::connect(specs, ","), 10000u).is_none();
+}
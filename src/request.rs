@@ -3,10 +3,17 @@ use io::WriterUtil;
 use core::path::{GenericPath};
 use core::send_map::linear::{LinearMap};
 use http_parser::{HttpRequest};
+use negotiation::{MediaRange};
 
-// TODO:
-// include last-modified and maybe etag
 pub fn process_request(config: &Config, tasks: &mut LinearMap<~str, ControlChan>, push_data: PushChan, request: HttpRequest, local_addr: &str, remote_addr: &str) -> (~str, Body)
+{
+	let (response, body) = prepare_response(config, tasks, push_data, request, local_addr, remote_addr);
+	make_header_and_body(&response, body)
+}
+
+/// Like process_request except that the response header isn't serialized (so that
+/// callers can inspect the response headers).
+pub fn prepare_response(config: &Config, tasks: &mut LinearMap<~str, ControlChan>, push_data: PushChan, request: HttpRequest, local_addr: &str, remote_addr: &str) -> (Response, Body)
 {
 	info!("Servicing %s for %s", request.method, utils::truncate_str(request.url, 80));
 	
@@ -14,18 +21,96 @@ pub fn process_request(config: &Config, tasks: &mut LinearMap<~str, ControlChan>
 	let (path, params) = parse_url(request.url);
 	let HttpRequest {body: move body, method: move method, headers: move headers, _} = request;
 	let request = Request {version: version, method:copy  method, local_addr: local_addr.to_owned(), remote_addr: remote_addr.to_owned(), 
-		path: path, matches: LinearMap(), params: params, headers: headers, body: body};
-	let types = if request.headers.contains_key(@~"accept") {str::split_char(request.headers.get(@~"accept"), ',')} else {~[~"text/html"]};
-	let (response, body) = get_body(config, tasks, push_data, &request, types);
+		path: path, matches: LinearMap(), params: params, headers: headers, body: body, negotiated_type: ~""};
+	
+	// Section 14.1 says that clients which don't send an Accept header accept every type.
+	let accept = negotiation::parse_accept(request.headers.find("Accept").get_default(~"*/*"));
+	let (response, body) = get_body(config, tasks, push_data, &request, accept);
+	let mut response = response;
+	
+	// Note that handle_connection relies on the Connection header to decide whether
+	// to close the connection.
+	if !is_persistent(&request)
+	{
+		response.headers.insert(~"Connection", ~"close");
+	}
+	else if request.version == ~"1.0" && !response.headers.contains_key("Connection")
+	{
+		response.headers.insert(~"Connection", ~"keep-alive");
+	}
+	let body = compress_body(config, &request, &mut response, body);
+	
+	// The length of a produced body isn't known up front so it is sent with the
+	// chunked transfer-coding (as are bodies with trailers). Section 3.6 says that
+	// transfer-codings must not be sent to HTTP/1.0 clients so those get the raw
+	// body which is ended by closing the connection.
+	if needs_chunking(&body)
+	{
+		response.headers.remove("Content-Length");
+		if request.version == ~"1.0"
+		{
+			response.headers.insert(~"Connection", ~"close");
+		}
+		else
+		{
+			if !response.headers.transfer_codings().contains(&~"chunked")
+			{
+				response.headers.add(~"Transfer-Encoding", ~"chunked");
+			}
+			
+			// Section 14.40 says that trailers should be declared with a Trailer header.
+			let names = trailer_names(&body);
+			if names.is_not_empty()
+			{
+				response.headers.insert(~"Trailer", str::connect(names, ", "));
+			}
+		}
+	}
 	
-	let (header, body) = make_header_and_body(&response, body);
-	debug!("response header: %s", header);
+	debug!("response headers: %?", response.headers);
 	debug!("response body: %?", body);		// TODO: only print this if its a text mime type (and convert it to a str if so)
 	
-	(header, body)
+	(response, body)
+}
+
+/// Used when a request can't be parsed (so there is no route or view). The response
+/// uses the bad-request.html template (or a built-in page if it is missing) and, in
+/// debug mode, the template context will have a parse-error entry with the reason
+/// the request was rejected.
+pub fn process_bad_request(config: &Config, mesg: &str, local_addr: &str, remote_addr: &str) -> (~str, Body)
+{
+	let request = Request {version: ~"1.1", method: ~"", local_addr: local_addr.to_owned(), remote_addr: remote_addr.to_owned(), 
+		path: ~"", matches: LinearMap(), params: LinearMap(), headers: Headers(), body: ~[], negotiated_type: ~"text/html"};
+	
+	let mut response = make_initial_response(config, status::BadRequest, ~"text/html; charset=UTF-8", &request);
+	response.headers.insert(~"Connection", ~"close");
+	if config.settings.contains_key(@~"debug") && config.settings.get(@~"debug") == ~"true"
+	{
+		response.context.insert(@~"parse-error", mustache::Str(@mesg.to_owned()));
+	}
+	
+	let (response, body) = process_template(config, Response {template: ~"bad-request.html", ..response}, &request);
+	make_header_and_body(&response, body)
+}
+
+// Section 8.1.2 says that HTTP/1.1 connections are persistent unless the client
+// sends "Connection: close". HTTP/1.0 connections are only persistent if the client
+// sends "Connection: keep-alive" (see section 19.6.2).
+priv fn is_persistent(request: &Request) -> bool
+{
+	let tokens = request.headers.connection();
+	
+	if request.version == ~"1.0"
+	{
+		vec::contains(tokens, &~"keep-alive")
+	}
+	else
+	{
+		!vec::contains(tokens, &~"close")
+	}
 }
 
-priv fn parse_url(url: &str) -> (~str, LinearMap<~str, ~str>)
+pub fn parse_url(url: &str) -> (~str, LinearMap<~str, ~str>)
 {
 	match str::find_char(url, '?')
 	{
@@ -68,9 +153,124 @@ priv fn parse_url(url: &str) -> (~str, LinearMap<~str, ~str>)
 	}
 }
 
-pub fn make_initial_response(config: &Config, status_code: ~str, status_mesg: ~str, mime_type: ~str, request: &Request) -> Response
+// Compresses the body with gzip or deflate if its type is in config.compress_types and
+// the client accepts one of those codings (see sections 3.5 and 14.3). Bodies which
+// already have a coding, are chunked, are byte ranges, are streamed from a file, or
+// have an explicit length are left alone. Compressed bodies get their own ETag.
+priv fn compress_body(config: &Config, request: &Request, response: &mut Response, body: Body) -> Body
+{
+	let mime_type = response.headers.content_type().get_default(~"");
+	if !is_compressible(config, mime_type) || response.headers.contains_key("Content-Encoding") || response.headers.contains_key("Transfer-Encoding") || response.headers.contains_key("Content-Range") || is_streamed(&body)
+	{
+		return body;
+	}
+	response.headers.add_vary("Accept-Encoding");
+	
+	if response.headers.content_length().get_default(0u) != 0u
+	{
+		return body;
+	}
+	
+	let ranges = negotiation::parse_qvalues(request.headers.find("Accept-Encoding").get_default(~""));
+	let gzip = negotiation::coding_quality(ranges, "gzip");
+	let deflate = negotiation::coding_quality(ranges, "deflate");
+	if gzip == 0.0 && deflate == 0.0
+	{
+		return body;
+	}
+	
+	let bytes = body_bytes(&body);
+	if bytes.is_empty()
+	{
+		return body;
+	}
+	
+	let (coding, compressed) = if gzip >= deflate {(~"gzip", compression::gzip(bytes))} else {(~"deflate", compression::deflate(bytes))};
+	debug!("compressed %? bytes to %? bytes using %s", bytes.len(), compressed.len(), coding);
+	match response.headers.etag()
+	{
+		option::Some(ref etag) => response.headers.set_etag(compression::coded_etag(*etag, coding)),
+		option::None => {}
+	}
+	response.headers.insert(~"Content-Encoding", coding);
+	BinaryBody(@compressed)
+}
+
+priv fn is_compressible(config: &Config, mime_type: &str) -> bool
+{
+	// These are already compressed so compressing them again is a waste of time.
+	if (mime_type.starts_with("image/") && mime_type != "image/svg+xml") || mime_type.starts_with("audio/") || mime_type.starts_with("video/") ||
+		vec::contains(~[~"application/zip", ~"application/gzip", ~"application/x-gzip", ~"application/x-bzip2"], &mime_type.to_owned())
+	{
+		return false;
+	}
+	
+	do config.compress_types.any |t|
+	{
+		*t == mime_type.to_owned() || *t == ~"*/*" || (t.ends_with("/*") && mime_type.starts_with(t.slice(0, t.len() - 1u)))
+	}
+}
+
+priv fn body_bytes(body: &Body) -> ~[u8]
+{
+	match *body
+	{
+		StringBody(text) =>
+		{
+			str::to_bytes(*text)
+		}
+		BinaryBody(binary) =>
+		{
+			copy *binary
+		}
+		CompoundBody(parts) =>
+		{
+			let mut bytes = ~[];
+			for parts.each |part| {vec::push_all(&mut bytes, body_bytes(*part));}
+			bytes
+		}
+		ReaderBody(*) | ProducerBody(*) | TrailedBody(*) =>
+		{
+			fail ~"streamed bodies should not be loaded into memory"
+		}
+	}
+}
+
+priv fn is_streamed(body: &Body) -> bool
+{
+	match *body
+	{
+		ReaderBody(*) | ProducerBody(*) | TrailedBody(*) => true,
+		CompoundBody(parts) => parts.any(|part| {is_streamed(*part)}),
+		_ => false,
+	}
+}
+
+/// Returns true if body is, or contains, a ProducerBody or a TrailedBody (these are
+/// sent using the chunked transfer-coding).
+pub fn needs_chunking(body: &Body) -> bool
+{
+	match *body
+	{
+		ProducerBody(*) | TrailedBody(*) => true,
+		CompoundBody(parts) => parts.any(|part| {needs_chunking(*part)}),
+		_ => false,
+	}
+}
+
+priv fn trailer_names(body: &Body) -> ~[~str]
+{
+	match *body
+	{
+		TrailedBody(inner, names, _trailers) => copy *names + trailer_names(inner),
+		CompoundBody(parts) => vec::concat(parts.map(|part| {trailer_names(*part)})),
+		_ => ~[],
+	}
+}
+
+pub fn make_initial_response(config: &Config, status: Status, mime_type: ~str, request: &Request) -> Response
 {
-	let mut headers = utils::linear_map_from_vector(~[
+	let mut headers = headers_from_vector(~[
 		(~"Content-Type", copy mime_type),
 		(~"Date", std::time::now_utc().rfc822()),
 		(~"Server", copy config.server_info),
@@ -83,14 +283,27 @@ pub fn make_initial_response(config: &Config, status_code: ~str, status_mesg: ~s
 	
 	let context = std::map::HashMap();
 	context.insert(@~"request-path", mustache::Str(@copy request.path));
-	context.insert(@~"status-code", mustache::Str(@copy status_code));
-	context.insert(@~"status-mesg", mustache::Str(@copy status_mesg));
+	context.insert(@~"status-code", mustache::Str(@status.code().to_str()));
+	context.insert(@~"status-mesg", mustache::Str(@status.reason()));
 	context.insert(@~"request-version", mustache::Str(@copy request.version));
 	
-	Response {status: status_code + ~" " + status_mesg, headers: headers, body: StringBody(@~""), template: ~"", context: context}
+	Response {status: status, headers: headers, body: StringBody(@~""), template: ~"", context: context}
+}
+
+/// Hard-coded html used for error responses whose template isn't available.
+pub fn error_body(status: &Status) -> ~str
+{
+	fmt!("<!DOCTYPE html>\n<meta charset=utf-8>\n\n<title>Error %? (%s)!</title>\n\n<p><b>%?</b> %s</p>", status.code(), status.reason(), status.code(), status.reason())
+}
+
+// These templates were added after the others so, to avoid breaking existing
+// sites, they are optional and a built-in page is used if they are missing.
+priv fn has_builtin_page(template: &str) -> bool
+{
+	template == "bad-request.html" || template == "not-acceptable.html" || template == "not-allowed.html"
 }
 
-priv fn body_len(body: &Body) -> uint
+pub fn body_len(body: &Body) -> uint
 {
 	match *body
 	{
@@ -106,28 +319,40 @@ priv fn body_len(body: &Body) -> uint
 		{
 			do parts.foldl(0) |result, part| {*result + body_len(*part)}
 		}
+		ReaderBody(_reader, _offset, len) =>
+		{
+			len
+		}
+		ProducerBody(_producer) =>
+		{
+			fail ~"produced bodies have no length"
+		}
+		TrailedBody(body, _names, _trailers) =>
+		{
+			body_len(body)
+		}
 	}
 }
 
+/// Frames body as a single chunk of the chunked transfer-coding (see section 3.6.1).
+pub fn make_chunk(body: Body) -> Body
+{
+	CompoundBody(@[@StringBody(@fmt!("%X\r\n", body_len(&body))), @body, @StringBody(@~"\r\n")])
+}
+
+/// Returns the status line and headers along with the body to send. Chunked bodies
+/// (e.g. server-sent events or a ProducerBody) are framed as they are written by the
+/// connection.
 pub fn make_header_and_body(response: &Response, body: Body) -> (~str, Body)
 {
 	let mut headers = ~"";
-	let mut has_content_len = false;
-	let mut is_chunked = false;
+	let has_content_len = response.headers.contains_key("Content-Length");
+	let is_chunked = response.headers.transfer_codings().contains(&~"chunked");
 	
 	for response.headers.each()
 	|name, value|
 	{
-		if *name == ~"Content-Length"
-		{
-			has_content_len = true;
-		}
-		else if *name == ~"Transfer-Encoding" && *value == ~"chunked"
-		{
-			is_chunked = true;
-		}
-		
-		if *name == ~"Content-Length" && *value == ~"0"
+		if headers::same_name(*name, "Content-Length") && *value == ~"0"
 		{
 			headers += fmt!("Content-Length: %?\r\n", body_len(&body));
 		}
@@ -141,36 +366,46 @@ pub fn make_header_and_body(response: &Response, body: Body) -> (~str, Body)
 	{
 		assert !has_content_len;
 	}
-	else if !has_content_len
+	else if !has_content_len && !needs_chunking(&body) && response.status != status::NotModified && response.status != status::NoContent
 	{
+		// Section 4.3 says that 204 and 304 responses have no body (so no length either).
 		headers += fmt!("Content-Length: %?\r\n", body_len(&body));
 	}
 	
-	(
-		fmt!("HTTP/1.1 %s\r\n%s\r\n", response.status, headers),
-		if is_chunked
-		{
-			CompoundBody(@[@StringBody(@fmt!("%X\r\n", body_len(&body))), @body, @StringBody(@~"\r\n")])
-		}
-		else
-		{
-			body
-		}
-	)
+	(fmt!("HTTP/1.1 %s\r\n%s\r\n", response.status.to_str(), headers), body)
 }
 
-priv fn get_body(config: &Config, tasks: &mut LinearMap<~str, ControlChan>, push_data: PushChan, request: &Request, types: ~[~str]) -> (Response, Body)
+priv fn get_body(config: &Config, tasks: &mut LinearMap<~str, ControlChan>, push_data: PushChan, request: &Request, accept: &[MediaRange]) -> (Response, Body)
 {
-	if vec::contains(types, &~"text/event-stream") 
+	if negotiation::accepts_explicitly(accept, "text/event-stream") && request.method != ~"HEAD"
 	{
 		process_sse(config, tasks, push_data, request)
 	}
+	else if request.method == ~"OPTIONS"
+	{
+		process_options(config, request)
+	}
 	else
 	{
-		let (status_code, status_mesg, mime_type, handler, matches) = find_handler(config, request.method, request.path, types, request.version);
+		let (status, mime_type, handler, matches) = find_handler(config, request.method, request.path, accept, request.version);
+		let available = available_types(config, request.method, request.path);
 		
-		let response = make_initial_response(config, status_code, status_mesg, mime_type, request);
-		let response = handler(config, &Request {matches: matches, ..*request}, response);
+		let mut response = make_initial_response(config, copy status, mime_type + ~"; charset=UTF-8", request);
+		if status == status::MethodNotAllowed
+		{
+			response.headers.insert(~"Allow", str::connect(allowed_methods(config, request.path), ~", "));
+		}
+		else if status == status::NotAcceptable
+		{
+			// Section 10.4.7 says that 406 responses should list the available types.
+			response.context.insert(@~"available-types", mustache::Str(@str::connect(available, ~", ")));
+		}
+		if available.len() > 1u
+		{
+			// Section 14.44 says that caches need to know that the response depends on Accept.
+			response.headers.add_vary("Accept");
+		}
+		let response = handler(config, &Request {matches: matches, negotiated_type: mime_type, ..*request}, response);
 		
 		if str::is_not_empty(response.template.to_str())
 		{
@@ -184,87 +419,251 @@ priv fn get_body(config: &Config, tasks: &mut LinearMap<~str, ControlChan>, push
 	}
 }
 
-priv fn find_handler(config: &Config, method: &str, request_path: &str, types: &[~str], version: &str) -> (~str, ~str, ~str, ResponseHandler, LinearMap<~str, ~str>)
+priv fn find_handler(config: &Config, method: &str, request_path: &str, accept: &[MediaRange], version: &str) -> (Status, ~str, ResponseHandler, LinearMap<~str, ~str>)
 {
 	let mut handler = option::None;
-	let mut status_code = ~"200";
-	let mut status_mesg = ~"OK";
-	let mut result_type = ~"text/html; charset=UTF-8";
+	let mut status = status::OK;
+	let mut result_type = ~"text/html";
 	let mut matches = LinearMap();
 	
 	// According to section 3.1 servers are supposed to accept new minor version editions.
 	if !str::starts_with(version, "1.")
 	{
-		status_code = ~"505";
-		status_mesg = ~"HTTP Version Not Supported";
-		let (_, _, _, h, _) = find_handler(config, method, ~"not-supported.html", ~[~"types/html"], ~"1.1");
-		handler = option::Some(h);
-		info!("responding with %s %s", status_code, status_mesg);
+		status = status::HTTPVersionNotSupported;
+		handler = option::Some(copy config.missing);
+		info!("responding with %s", status.to_str());
 	}
 	
-	// See if the url matches a file under the resource root (i.e. the url can't have too many .. components).
+	// See if the url matches a file under the resource root or a mount (i.e. the url can't have too many .. components).
+	// Files can only be fetched so other methods fall through to the routes (or 405).
 	if option::is_none(&handler)
 	{
-		let path = utils::url_to_path(&config.resources_root, request_path);
-		let path = path.normalize();
-		if str::starts_with(path.to_str(), config.resources_root.to_str())
+		match url_to_rsrc(config, request_path)
 		{
-			if (config.valid_rsrc)(&path)
+			option::Some(ref path) =>
 			{
-				let mime_type = path_to_type(config, request_path);
-				if vec::contains(types, &~"*/*") || vec::contains(types, &mime_type)
+				if method_matches("GET", method) && (config.valid_rsrc)(path)
 				{
-					result_type = mime_type + ~"; charset=UTF-8";
-					handler = option::Some(copy config.static_handler);
+					let mime_type = path_to_type(config, request_path);
+					if negotiation::media_quality(accept, mime_type) > 0.0
+					{
+						result_type = mime_type;
+						handler = option::Some(copy config.static_handler);
+					}
 				}
 			}
-		}
-		else
-		{
-			status_code = ~"403";			// don't allow access to files not under resources_root
-			status_mesg = ~"Forbidden";
-			let (_, _, _, h, _) = find_handler(config, method, ~"forbidden.html", ~[~"types/html"], version);
-			handler = option::Some(h);
-			info!("responding with %s %s (path wasn't under resources_root)", status_code, status_mesg);
+			option::None =>
+			{
+				status = status::Forbidden;			// don't allow access to files not under their root
+				handler = option::Some(copy config.missing);
+				info!("responding with %s (path wasn't under its root)", status.to_str());
+			}
 		}
 	}
 	
-	// Then look for the first matching route.
+	// Then look for the matching route and type the client most prefers (if there
+	// are ties the first route and type wins).
 	if option::is_none(&handler)
 	{
+		let mut best = 0.0;
 		for vec::each(config.routes) |entry|
 		{
-			if str::eq_slice(entry.method, method)
+			if method_matches(entry.method, method)
 			{
 				let m = uri_template::match_template(request_path, entry.template);
 				if m.len() > 0u
 				{
-					if vec::contains(types, &entry.mime_type)
+					let (quality, mime_type) = negotiation::best_type(accept, entry.mime_types());
+					if quality > best
 					{
 						handler = option::Some(config.views.get(&entry.route));
-						result_type = entry.mime_type + ~"; charset=UTF-8";
+						result_type = mime_type;
 						matches = m;
-						break;
+						best = quality;
 					}
-					else
+					else if quality == 0.0
 					{
-						info!("request matches route but route type is %s not one of: %s", entry.mime_type, str::connect(types, ~", "));
+						info!("request matches route %s but the client doesn't accept %s", entry.route, str::connect(entry.mime_types(), ~", "));
 					}
 				}
 			}
 		}
 	}
 	
+	// Directories beneath the resource root are handled by the static handler too (but
+	// routes take precedence so that, for example, "/" can be a route).
+	if option::is_none(&handler) && method_matches("GET", method) && serves_directory(config, request_path) && negotiation::media_quality(accept, "text/html") > 0.0
+	{
+		result_type = ~"text/html";
+		handler = option::Some(copy config.static_handler);
+	}
+	
+	// If the path matches a route or file but the client doesn't accept its type use the not acceptable handler.
+	if option::is_none(&handler)
+	{
+		let available = available_types(config, method, request_path);
+		if vec::is_not_empty(available)
+		{
+			status = status::NotAcceptable;
+			handler = option::Some(not_acceptable_view);
+			info!("responding with %s (available types are %s)", status.to_str(), str::connect(available, ~", "));
+		}
+	}
+	
+	// If the path matches a route with a different method use the not allowed handler.
+	if option::is_none(&handler)
+	{
+		let allowed = allowed_methods(config, request_path);
+		if vec::is_not_empty(allowed) && !vec::contains(allowed, &method.to_owned())
+		{
+			status = status::MethodNotAllowed;
+			handler = option::Some(not_allowed_view);
+			info!("responding with %s (allowed methods are %s)", status.to_str(), str::connect(allowed, ~", "));
+		}
+	}
+	
 	// Otherwise use the missing handler.
 	if option::is_none(&handler)
 	{
-		status_code = ~"404";
-		status_mesg = ~"Not Found";
+		status = status::NotFound;
 		handler = option::Some(copy(config.missing));
-		info!("responding with %s %s", status_code, status_mesg);
+		info!("responding with %s", status.to_str());
+	}
+	
+	return (status, result_type, option::get(handler), matches);
+}
+
+// HEAD requests are handled by GET routes (handle_connection will drop the body).
+priv fn method_matches(route_method: &str, method: &str) -> bool
+{
+	str::eq_slice(route_method, method) || (str::eq_slice(method, "HEAD") && str::eq_slice(route_method, "GET"))
+}
+
+priv fn not_acceptable_view(_config: &Config, _request: &Request, response: Response) -> Response
+{
+	Response {template: ~"not-acceptable.html", ..response}
+}
+
+priv fn not_allowed_view(_config: &Config, _request: &Request, response: Response) -> Response
+{
+	Response {template: ~"not-allowed.html", ..response}
+}
+
+// Returns the mime types of the static file and the routes which match method and request_path.
+priv fn available_types(config: &Config, method: &str, request_path: &str) -> ~[~str]
+{
+	let mut types = ~[];
+	
+	let path = url_to_rsrc(config, request_path);
+	if method_matches("GET", method) && path.is_some() && (config.valid_rsrc)(path.get_ref())
+	{
+		vec::push(&mut types, path_to_type(config, request_path));
+	}
+	
+	for vec::each(config.routes) |entry|
+	{
+		if method_matches(entry.method, method) && uri_template::match_template(request_path, entry.template).len() > 0u
+		{
+			for vec::each(entry.mime_types()) |mime_type|
+			{
+				if !vec::contains(types, mime_type)
+				{
+					vec::push(&mut types, copy *mime_type);
+				}
+			}
+		}
+	}
+	
+	types
+}
+
+// Returns true if the path is a directory which has an index file or can be listed (or
+// needs to be redirected to the URL ending with a slash).
+priv fn serves_directory(config: &Config, request_path: &str) -> bool
+{
+	match url_to_rsrc(config, request_path)
+	{
+		option::Some(ref path) =>
+			(config.list_rsrc)(path).is_some() && (!request_path.ends_with("/") || config.autoindex.is_not_empty() || find_index_file(config, path).is_some()),
+		option::None =>
+			false,
+	}
+}
+
+// Section 9.2 says that OPTIONS requests return the methods which can be used
+// with a resource (or with any resource for "OPTIONS *").
+priv fn process_options(config: &Config, request: &Request) -> (Response, Body)
+{
+	let allowed = allowed_methods(config, request.path);
+	if vec::is_not_empty(allowed)
+	{
+		let mut response = make_initial_response(config, status::OK, ~"text/html; charset=UTF-8", request);
+		response.headers.insert(~"Allow", str::connect(allowed, ~", "));
+		(response, StringBody(@~""))
+	}
+	else
+	{
+		info!("responding with 404 Not Found");
+		let response = make_initial_response(config, status::NotFound, ~"text/html; charset=UTF-8", request);
+		let response = (config.missing)(config, request, response);
+		process_template(config, response, request)
+	}
+}
+
+// Returns the methods which can be used with request_path: the methods of the
+// routes whose templates match the path and GET for static files. HEAD and
+// OPTIONS are implicitly supported. If request_path is "*" then all the methods
+// the server supports are returned.
+priv fn allowed_methods(config: &Config, request_path: &str) -> ~[~str]
+{
+	let mut methods = ~[];
+	
+	let path = url_to_rsrc(config, request_path);
+	if request_path == "*" || (path.is_some() && (config.valid_rsrc)(path.get_ref()))
+	{
+		vec::push(&mut methods, ~"GET");
+	}
+	
+	for vec::each(config.routes) |entry|
+	{
+		if !vec::contains(methods, &entry.method)
+		{
+			if request_path == "*" || uri_template::match_template(request_path, entry.template).len() > 0u
+			{
+				vec::push(&mut methods, copy entry.method);
+			}
+		}
 	}
 	
-	return (status_code, status_mesg, result_type, option::get(handler), matches);
+	if vec::is_not_empty(methods)
+	{
+		if vec::contains(methods, &~"GET") && !vec::contains(methods, &~"HEAD")
+		{
+			vec::push(&mut methods, ~"HEAD");
+		}
+		if !vec::contains(methods, &~"OPTIONS")
+		{
+			vec::push(&mut methods, ~"OPTIONS");
+		}
+	}
+	
+	pure fn le(a: &~str, b: &~str) -> bool {*a <= *b}
+	std::sort::merge_sort(methods, le)
+}
+
+/// Returns the name of the first route matching the method and url (or empty if no route matches).
+/// Note that this ignores the route mime types.
+pub fn find_route_name(config: &Config, method: &str, url: &str) -> ~str
+{
+	let (path, _) = parse_url(url);
+	for vec::each(config.routes) |entry|
+	{
+		if str::eq_slice(entry.method, method) && uri_template::match_template(path, entry.template).len() > 0u
+		{
+			return copy entry.route;
+		}
+	}
+	~""
 }
 
 priv fn load_template(config: &Config, path: &Path) -> result::Result<@~str, ~str>
@@ -318,14 +717,38 @@ priv fn load_template(config: &Config, path: &Path) -> result::Result<@~str, ~st
 
 priv fn process_template(config: &Config, response: Response, request: &Request) -> (Response, Body)
 {
-	let path = utils::url_to_path(&config.resources_root, response.template);
+	let (template, language) = localize_template(config, response.template, request);
+	let path = url_to_rsrc(config, template);
+	let loaded = match path
+	{
+		option::Some(ref path) => load_template(config, path),
+		option::None => result::Err(~"path isn't beneath its root"),
+	};
 	let (response, body) =
-		match load_template(config, &path)
+		match loaded
 		{
 			result::Ok(v) =>
 			{
 				// We found a legit template file.
-				(Response {status: copy response.status, ..response}, v)		// hacky way to return a new Response without a copy
+				let mut response = Response {status: copy response.status, ..response};		// hacky way to return a new Response without a copy
+				if language.is_not_empty()
+				{
+					response.headers.insert(~"Content-Language", copy language);
+					if config.languages.len() > 1u
+					{
+						response.headers.add_vary("Accept-Language");
+					}
+					if response.context.size() > 0u
+					{
+						response.context.insert(@~"language", mustache::Str(@copy language));
+					}
+				}
+				(response, v)
+			}
+			result::Err(_) if has_builtin_page(response.template) =>
+			{
+				let body = error_body(&response.status);
+				(Response {context: std::map::HashMap(), ..response}, @body)
 			}
 			result::Err(ref mesg) =>
 			{
@@ -336,13 +759,13 @@ priv fn process_template(config: &Config, response: Response, request: &Request)
 				
 				if config.server_info != ~"unit test"
 				{
-					error!("Error '%s' tying to read '%s'", *mesg, path.to_str());
+					error!("Error '%s' tying to read '%s'", *mesg, template);
 				}
-				(make_initial_response(config, ~"403", ~"Forbidden", ~"text/html; charset=UTF-8", request), @body)
+				(make_initial_response(config, status::Forbidden, ~"text/html; charset=UTF-8", request), @body)
 			}
 		};
 	
-	if !str::starts_with(response.status, "403") && response.context.size() > 0u
+	if response.status != status::Forbidden && response.context.size() > 0u
 	{
 		// If we were able to load a template, and we have context, then use the
 		// context to expand the template.
@@ -359,6 +782,40 @@ priv fn process_template(config: &Config, response: Response, request: &Request)
 	}
 }
 
+// If config.languages has more than one language then this returns the template for
+// the language the client prefers along with the language, e.g. ("home.fr.html", "fr").
+// Templates in the default language (the first language) have no language extension.
+priv fn localize_template(config: &Config, template: &str, request: &Request) -> (~str, ~str)
+{
+	if config.languages.len() < 2u
+	{
+		return (template.to_owned(), if config.languages.is_empty() {~""} else {copy config.languages[0]});
+	}
+	
+	let ranges = negotiation::parse_qvalues(request.headers.find("Accept-Language").get_default(~""));
+	for negotiation::preferred_languages(ranges, config.languages).each |language|
+	{
+		if *language == config.languages[0]
+		{
+			break;
+		}
+		
+		let localized = match str::rfind_char(template, '.')
+		{
+			option::Some(i) if i > 0u && !str::contains(template.slice(i, template.len()), "/") =>
+				fmt!("%s.%s%s", template.slice(0, i), *language, template.slice(i, template.len())),
+			_ =>
+				fmt!("%s.%s", template, *language),
+		};
+		if url_to_rsrc(config, localized).map_default(false, |path| {(config.valid_rsrc)(path)})
+		{
+			return (localized, copy *language);
+		}
+	}
+	
+	(template.to_owned(), copy config.languages[0])
+}
+
 priv fn url_dirname(path: &str) -> ~str
 {
 	match str::find_char(path, '/')
@@ -370,29 +827,17 @@ priv fn url_dirname(path: &str) -> ~str
 
 priv fn path_to_type(config: &Config, path: &str) -> ~str
 {
-	let p: path::Path = GenericPath::from_str(path);
-	let extension: Option<~str> = p.filetype();
-	if extension.is_some()
+	match rsrc_type(config, path)
 	{
-		assert extension.get_ref().char_at(0) == '.';
-		
-		match config.static_types.find(&extension.get())
+		option::Some(move v) =>
 		{
-			option::Some(v) =>
-			{
-				copy v
-			}
-			option::None =>
-			{
-				warn!("Couldn't find a static_types entry for %s", path);
-				~"text/html"
-			}
+			v
+		}
+		option::None =>
+		{
+			warn!("Couldn't find a static_types entry for %s", path);
+			~"text/html"
 		}
-	}
-	else
-	{
-		warn!("Can't determine mime type for %s", path);
-		~"text/html"
 	}
 }
 
@@ -417,14 +862,14 @@ fn err_loader(path: &Path) -> result::Result<~[u8], ~str>
 #[cfg(test)]
 fn make_request(url: ~str, mime_type: ~str) -> HttpRequest
 {
-	let headers = utils::linear_map_from_vector(~[		// http_parser lower cases header names so we do too
-		(~"host", ~"localhost:8080"),
-		(~"user-agent", ~"Mozilla/5.0"),
-		(~"accept", copy mime_type),
-		(~"accept-Language", ~"en-us,en"),
-		(~"accept-encoding", ~"gzip, deflate"),
-		(~"connection", ~"keep-alive")]);
-	HttpRequest {method: ~"GET", major_version: 1, minor_version: 1, url: url, headers: headers, body: ~""}
+	let headers = headers_from_vector(~[
+		(~"Host", ~"localhost:8080"),
+		(~"User-Agent", ~"Mozilla/5.0"),
+		(~"Accept", copy mime_type),
+		(~"Accept-Language", ~"en-us,en"),
+		(~"Accept-Encoding", ~"gzip, deflate"),
+		(~"Connection", ~"keep-alive")]);
+	HttpRequest {method: ~"GET", major_version: 1, minor_version: 1, url: url, headers: headers, body: ~[]}
 }
 
 #[test]
@@ -468,9 +913,9 @@ fn route_with_bad_type()
 	let request = make_request(~"/foo/bar", ~"text/zzz");
 	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
 	
-	assert header.contains("404 Not Found");
+	assert header.contains("406 Not Acceptable");
 	assert header.contains("Content-Type: text/html");
-	assert body.to_str() == ~"server/html/not-found.html contents";
+	assert body.to_str() == ~"server/html/not-acceptable.html contents";
 }
 
 #[test]
@@ -505,7 +950,7 @@ fn static_route()
 		routes: ~[Route(~"foo", ~"GET", ~"/foo/bar")],
 		views: utils::linear_map_from_vector(~[(~"foo",  test_view)]),
 		load_rsrc: null_loader,
-		valid_rsrc: |_path| {true}
+		valid_rsrc: |path| {!path.to_str().ends_with(".gz")}
 		, ..initialize_config()};
 		
 	let mut tasks = LinearMap();
@@ -543,8 +988,9 @@ fn static_with_bad_type()
 	let request = make_request(~"/foo/baz.jpg", ~"text/zzz");
 	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
 	
+	assert header.contains("406 Not Acceptable");
 	assert header.contains("Content-Type: text/html");
-	assert body.to_str() == ~"server/html/not-found.html contents";
+	assert body.to_str() == ~"server/html/not-acceptable.html contents";
 }
 
 #[test]
@@ -595,6 +1041,11 @@ fn path_outside_root()
 	assert header.contains("Content-Type: text/html");
 	assert header.contains("403 Forbidden");
 	assert str::contains(body.to_str(), "server/html/not-found.html contents");
+	
+	// Directories whose names start with the root's name are not beneath the root.
+	let request = make_request(~"/../html-secret/baz.jpg", ~"text/html,image/jpeg");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("403 Forbidden");
 }
 
 #[test]
@@ -699,3 +1150,931 @@ fn query_strings()
 	assert utils::check_strs(path, ~"/some");
 	assert utils::check_vectors(utils::vector_from_linear_map(&params), ~[(~"name", ~"value"), (~"foo", ~"bar")]);
 }
+
+#[test]
+fn binary_body()
+{
+	fn upload_view(_config: &Config, request: &Request, response: Response) -> Response
+	{
+		assert request.body == ~[0xFFu8, 0x00u8, 0x89u8, 0x50u8];
+		assert request.body_text().is_none();
+		Response {template: ~"test.html", ..response}
+	}
+	
+	let config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		routes: ~[Route(~"upload", ~"POST", ~"/upload")],
+		views: utils::linear_map_from_vector(~[(~"upload",  upload_view)]),
+		load_rsrc: null_loader
+		, .. initialize_config()};
+		
+	let mut tasks = LinearMap();
+	let sse_port = oldcomm::Port();
+	let sse_chan = oldcomm::Chan(&sse_port);
+	
+	let request = HttpRequest {method: ~"POST", body: ~[0xFFu8, 0x00u8, 0x89u8, 0x50u8], .. make_request(~"/upload", ~"text/html")};
+	let (_header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	
+	assert body.to_str() == ~"server/html/test.html contents";
+}
+
+#[test]
+fn bad_request()
+{
+	fn context_loader(_path: &Path) -> result::Result<~[u8], ~str>
+	{
+		result::Ok(str::to_bytes(~"{{status-code}}: {{parse-error}}"))
+	}
+	
+	let config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		load_rsrc: null_loader
+		, .. initialize_config()};
+	
+	let (header, body) = process_bad_request(&config, ~"Expected HTTP version on line 1 col 8", ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("400 Bad Request");
+	assert header.contains("Connection: close");
+	assert body.to_str() == ~"server/html/bad-request.html contents";
+	
+	let config = Config {load_rsrc: context_loader, settings: utils::linear_map_from_vector(~[(~"debug", ~"true")]), .. config};
+	let (_header, body) = process_bad_request(&config, ~"Expected HTTP version on line 1 col 8", ~"10.11.12.13", ~"1.2.3.4");
+	assert utils::check_strs(body.to_str(), ~"400: Expected HTTP version on line 1 col 8");
+}
+
+#[test]
+fn connection_close()
+{
+	let config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		routes: ~[Route(~"foo", ~"GET", ~"/foo/bar")],
+		views: utils::linear_map_from_vector(~[(~"foo",  test_view)]),
+		load_rsrc: null_loader
+		, .. initialize_config()};
+		
+	let mut tasks = LinearMap();
+	let sse_port = oldcomm::Port();
+	let sse_chan = oldcomm::Chan(&sse_port);
+	
+	let request = make_request(~"/foo/bar", ~"text/html");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert !header.contains("Connection:");
+	
+	let mut request = make_request(~"/foo/bar", ~"text/html");
+	request.headers.insert(~"connection", ~"Close");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("Connection: close");
+	
+	let mut request = HttpRequest {minor_version: 0, .. make_request(~"/foo/bar", ~"text/html")};
+	request.headers.remove("connection");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("Connection: close");
+	
+	let request = HttpRequest {minor_version: 0, .. make_request(~"/foo/bar", ~"text/html")};
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("Connection: keep-alive");
+}
+
+#[test]
+fn head_request()
+{
+	let config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		routes: ~[Route(~"foo", ~"GET", ~"/foo/bar")],
+		views: utils::linear_map_from_vector(~[(~"foo",  test_view)]),
+		load_rsrc: null_loader,
+		valid_rsrc: |path| {path.to_str().ends_with(".jpg")}
+		, .. initialize_config()};
+		
+	let mut tasks = LinearMap();
+	let sse_port = oldcomm::Port();
+	let sse_chan = oldcomm::Chan(&sse_port);
+	
+	let request = HttpRequest {method: ~"HEAD", .. make_request(~"/foo/bar", ~"text/html")};
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("200 OK");
+	assert header.contains(fmt!("Content-Length: %?", "server/html/test.html contents".len()));
+	
+	let request = HttpRequest {method: ~"HEAD", .. make_request(~"/foo/baz.jpg", ~"image/jpeg")};
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("200 OK");
+	assert header.contains("Content-Type: image/jpeg");
+	assert header.contains(fmt!("Content-Length: %?", "server/html/foo/baz.jpg contents".len()));
+}
+
+#[test]
+fn method_not_allowed()
+{
+	let config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		routes: ~[Route(~"foo", ~"GET", ~"/foo/{name}"), Route(~"update", ~"PUT", ~"/foo/{name}")],
+		views: utils::linear_map_from_vector(~[(~"foo",  test_view), (~"update",  test_view)]),
+		load_rsrc: null_loader,
+		valid_rsrc: |_path| {false}
+		, .. initialize_config()};
+		
+	let mut tasks = LinearMap();
+	let sse_port = oldcomm::Port();
+	let sse_chan = oldcomm::Chan(&sse_port);
+	
+	let request = HttpRequest {method: ~"DELETE", .. make_request(~"/foo/bar", ~"text/html")};
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("405 Method Not Allowed");
+	assert header.contains("Allow: GET, HEAD, OPTIONS, PUT");
+	assert body.to_str() == ~"server/html/not-allowed.html contents";
+	
+	let request = HttpRequest {method: ~"DELETE", .. make_request(~"/baz", ~"text/html")};
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("404 Not Found");
+}
+
+#[test]
+fn post_to_static_file()
+{
+	let config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		load_rsrc: null_loader,
+		valid_rsrc: |path| {path.to_str().ends_with(".jpg")}
+		, .. initialize_config()};
+		
+	let mut tasks = LinearMap();
+	let sse_port = oldcomm::Port();
+	let sse_chan = oldcomm::Chan(&sse_port);
+	
+	let request = HttpRequest {method: ~"POST", .. make_request(~"/foo/baz.jpg", ~"image/jpeg")};
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("405 Method Not Allowed");
+	assert header.contains("Allow: GET, HEAD, OPTIONS");
+	assert body.to_str() == ~"server/html/not-allowed.html contents";
+	
+	let request = HttpRequest {method: ~"HEAD", .. make_request(~"/foo/baz.jpg", ~"image/jpeg")};
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("200 OK");
+}
+
+#[test]
+fn missing_not_allowed_template()
+{
+	let config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		routes: ~[Route(~"foo", ~"GET", ~"/foo/{name}")],
+		views: utils::linear_map_from_vector(~[(~"foo",  test_view)]),
+		load_rsrc: err_loader,
+		valid_rsrc: |_path| {false}
+		, .. initialize_config()};
+		
+	let mut tasks = LinearMap();
+	let sse_port = oldcomm::Port();
+	let sse_chan = oldcomm::Chan(&sse_port);
+	
+	let request = HttpRequest {method: ~"DELETE", .. make_request(~"/foo/bar", ~"text/html")};
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("405 Method Not Allowed");
+	assert body.to_str().contains("<p><b>405</b> Method Not Allowed</p>");
+}
+
+#[test]
+fn options_request()
+{
+	let config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		routes: ~[Route(~"foo", ~"GET", ~"/foo/{name}"), Route(~"comment", ~"POST", ~"/comment")],
+		views: utils::linear_map_from_vector(~[(~"foo",  test_view), (~"comment",  test_view)]),
+		load_rsrc: null_loader,
+		valid_rsrc: |path| {path.to_str().ends_with(".jpg")}
+		, .. initialize_config()};
+		
+	let mut tasks = LinearMap();
+	let sse_port = oldcomm::Port();
+	let sse_chan = oldcomm::Chan(&sse_port);
+	
+	let request = HttpRequest {method: ~"OPTIONS", .. make_request(~"/comment", ~"text/html")};
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("200 OK");
+	assert header.contains("Allow: OPTIONS, POST");
+	assert header.contains("Content-Length: 0");
+	assert body.to_str() == ~"";
+	
+	let request = HttpRequest {method: ~"OPTIONS", .. make_request(~"/images/baz.jpg", ~"text/html")};
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("Allow: GET, HEAD, OPTIONS");
+	
+	let request = HttpRequest {method: ~"OPTIONS", .. make_request(~"*", ~"text/html")};
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("Allow: GET, HEAD, OPTIONS, POST");
+	
+	let request = HttpRequest {method: ~"OPTIONS", .. make_request(~"/missing", ~"text/html")};
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("404 Not Found");
+}
+
+#[test]
+fn view_status()
+{
+	fn created_view(_config: &Config, _request: &Request, response: Response) -> Response
+	{
+		Response {status: status::Created, template: ~"test.html", ..response}
+	}
+	
+	let config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		routes: ~[Route(~"foo", ~"GET", ~"/foo/bar")],
+		views: utils::linear_map_from_vector(~[(~"foo",  created_view)]),
+		load_rsrc: null_loader
+		, .. initialize_config()};
+		
+	let mut tasks = LinearMap();
+	let sse_port = oldcomm::Port();
+	let sse_chan = oldcomm::Chan(&sse_port);
+	
+	let request = make_request(~"/foo/bar", ~"text/html");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 201 Created\r\n");
+	assert body.to_str() == ~"server/html/test.html contents";
+}
+
+#[test]
+fn accept_quality()
+{
+	fn csv_view(_config: &Config, _request: &Request, response: Response) -> Response
+	{
+		Response {template: ~"report.csv", ..response}
+	}
+	
+	let config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		routes: ~[TypedRoute(~"html", ~"GET", ~"/report", ~"text/html"), TypedRoute(~"csv", ~"GET", ~"/report", ~"text/csv")],
+		views: utils::linear_map_from_vector(~[(~"html",  test_view), (~"csv",  csv_view)]),
+		load_rsrc: null_loader
+		, .. initialize_config()};
+		
+	let mut tasks = LinearMap();
+	let sse_port = oldcomm::Port();
+	let sse_chan = oldcomm::Chan(&sse_port);
+	
+	let request = make_request(~"/report", ~"text/html;q=0.5, text/csv");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("Content-Type: text/csv");
+	assert body.to_str() == ~"server/html/report.csv contents";
+	
+	let request = make_request(~"/report", ~"text/*;q=0.5, text/html");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("Content-Type: text/html");
+	assert body.to_str() == ~"server/html/test.html contents";
+	
+	let request = make_request(~"/report", ~"text/*");
+	let (_header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert body.to_str() == ~"server/html/test.html contents";
+	
+	let request = make_request(~"/report", ~"image/*, text/html;q=0");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("406 Not Acceptable");
+}
+
+#[test]
+fn negotiated_route()
+{
+	fn report_view(_config: &Config, request: &Request, response: Response) -> Response
+	{
+		if request.negotiated_type == ~"application/json"
+		{
+			Response {template: ~"report.json", ..response}
+		}
+		else
+		{
+			Response {template: ~"report.html", ..response}
+		}
+	}
+	
+	let config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		routes: ~[NegotiatedRoute(~"report", ~"GET", ~"/report", ~[~"text/html", ~"application/json"]), Route(~"foo", ~"GET", ~"/foo/bar")],
+		views: utils::linear_map_from_vector(~[(~"report",  report_view), (~"foo",  test_view)]),
+		load_rsrc: null_loader
+		, .. initialize_config()};
+		
+	let mut tasks = LinearMap();
+	let sse_port = oldcomm::Port();
+	let sse_chan = oldcomm::Chan(&sse_port);
+	
+	let request = make_request(~"/report", ~"application/json, text/html;q=0.8");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("Content-Type: application/json");
+	assert header.contains("Vary: Accept\r\n");
+	assert body.to_str() == ~"server/html/report.json contents";
+	
+	let request = make_request(~"/report", ~"text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("Content-Type: text/html");
+	assert body.to_str() == ~"server/html/report.html contents";
+	
+	let request = make_request(~"/report", ~"image/png");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("406 Not Acceptable");
+	assert header.contains("Vary: Accept\r\n");
+	
+	let request = make_request(~"/foo/bar", ~"text/html");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert !header.contains("Vary:");
+}
+
+#[test]
+fn localized_template()
+{
+	fn home_view(_config: &Config, _request: &Request, response: Response) -> Response
+	{
+		Response {template: ~"home.html", ..response}
+	}
+	
+	let config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		routes: ~[Route(~"home", ~"GET", ~"/home")],
+		views: utils::linear_map_from_vector(~[(~"home",  home_view)]),
+		load_rsrc: |path| {result::Ok(str::to_bytes(path.to_str() + ~" {{language}}"))},
+		valid_rsrc: |path| {!str::contains(path.to_str(), ".de.")},
+		languages: ~[~"en", ~"fr", ~"de"]
+		, .. initialize_config()};
+		
+	let mut tasks = LinearMap();
+	let sse_port = oldcomm::Port();
+	let sse_chan = oldcomm::Chan(&sse_port);
+	
+	let mut request = make_request(~"/home", ~"text/html");
+	request.headers.insert(~"Accept-Language", ~"fr-ca, fr;q=0.8, en;q=0.5");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("Content-Language: fr\r\n");
+	assert header.contains("Vary: Accept-Language\r\n");
+	assert utils::check_strs(body.to_str(), ~"server/html/home.fr.html fr");
+	
+	// There is no German template so we fall back to the client's next choice.
+	let mut request = make_request(~"/home", ~"text/html");
+	request.headers.insert(~"Accept-Language", ~"de, en;q=0.5, fr;q=0.2");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("Content-Language: en\r\n");
+	assert utils::check_strs(body.to_str(), ~"server/html/home.html en");
+	
+	let mut request = make_request(~"/home", ~"text/html");
+	request.headers.remove("Accept-Language");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("Content-Language: en\r\n");
+	assert utils::check_strs(body.to_str(), ~"server/html/home.html en");
+}
+
+#[test]
+fn compressed_response()
+{
+	let config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		routes: ~[Route(~"foo", ~"GET", ~"/foo/bar")],
+		views: utils::linear_map_from_vector(~[(~"foo",  test_view)]),
+		load_rsrc: null_loader,
+		valid_rsrc: |path| {path.to_str().ends_with(".jpg")},
+		compress_types: ~[~"text/*", ~"image/*"]
+		, .. initialize_config()};
+		
+	let mut tasks = LinearMap();
+	let sse_port = oldcomm::Port();
+	let sse_chan = oldcomm::Chan(&sse_port);
+	
+	let request = make_request(~"/foo/bar", ~"text/html");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("Content-Encoding: gzip\r\n");
+	assert header.contains("Vary: Accept-Encoding\r\n");
+	match body
+	{
+		BinaryBody(binary) =>
+		{
+			assert binary[0] == 0x1Fu8 && binary[1] == 0x8Bu8;
+			assert header.contains(fmt!("Content-Length: %?\r\n", binary.len()));
+		}
+		_ => fail fmt!("Expected binary body but found %?", body),
+	}
+	
+	let mut request = make_request(~"/foo/bar", ~"text/html");
+	request.headers.insert(~"Accept-Encoding", ~"gzip;q=0.5, deflate");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("Content-Encoding: deflate\r\n");
+	
+	let mut request = make_request(~"/foo/bar", ~"text/html");
+	request.headers.remove("Accept-Encoding");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert !header.contains("Content-Encoding:");
+	assert header.contains("Vary: Accept-Encoding\r\n");
+	assert body.to_str() == ~"server/html/test.html contents";
+	
+	// Images are already compressed.
+	let request = make_request(~"/foo/baz.jpg", ~"image/jpeg");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("Content-Type: image/jpeg");
+	assert !header.contains("Content-Encoding:");
+	assert !header.contains("Vary: Accept-Encoding");
+}
+
+#[test]
+fn precompressed_static()
+{
+	let config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		load_rsrc: null_loader,
+		valid_rsrc: |path| {path.to_str().ends_with(".js") || path.to_str().ends_with(".js.gz")},
+		compress_types: ~[~"text/*"]
+		, .. initialize_config()};
+		
+	let mut tasks = LinearMap();
+	let sse_port = oldcomm::Port();
+	let sse_chan = oldcomm::Chan(&sse_port);
+	
+	let request = make_request(~"/scripts/app.js", ~"*/*");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("Content-Type: text/javascript");
+	assert header.contains("Content-Encoding: gzip\r\n");
+	assert header.contains("Vary: Accept-Encoding\r\n");
+	match body
+	{
+		BinaryBody(binary) => assert utils::check_strs(str::from_bytes(*binary), ~"server/html/scripts/app.js.gz contents"),
+		_ => fail fmt!("Expected binary body but found %?", body),
+	}
+	
+	let mut request = make_request(~"/scripts/app.js", ~"*/*");
+	request.headers.insert(~"Accept-Encoding", ~"identity");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert !header.contains("Content-Encoding:");
+	assert header.contains("Vary: Accept-Encoding\r\n");
+	assert body.to_str() == ~"server/html/scripts/app.js contents";
+}
+
+#[test]
+fn conditional_get()
+{
+	let config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		load_rsrc: null_loader,
+		valid_rsrc: |path| {path.to_str().ends_with(".jpg")},
+		rsrc_info: |_path| {option::Some(RsrcMetadata {size: 100u, modified: 784887151i64})}
+		, .. initialize_config()};
+		
+	let mut tasks = LinearMap();
+	let sse_port = oldcomm::Port();
+	let sse_chan = oldcomm::Chan(&sse_port);
+	
+	let request = make_request(~"/images/baz.jpg", ~"image/jpeg");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 200 OK\r\n");
+	assert header.contains("Last-Modified: Tue, 15 Nov 1994 08:12:31 GMT\r\n");
+	assert header.contains("ETag: \"64-2ec86d6f\"\r\n");
+	assert body.to_str() == ~"<binary data>";
+	
+	let mut request = make_request(~"/images/baz.jpg", ~"image/jpeg");
+	request.headers.insert(~"If-None-Match", ~"\"xxx\", W/\"64-2ec86d6f\"");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 304 Not Modified\r\n");
+	assert header.contains("ETag: \"64-2ec86d6f\"\r\n");
+	assert !header.contains("Content-Length:");
+	assert body.to_str() == ~"";
+	
+	let mut request = make_request(~"/images/baz.jpg", ~"image/jpeg");
+	request.headers.insert(~"If-None-Match", ~"\"xxx\"");
+	request.headers.insert(~"If-Modified-Since", ~"Tue, 15 Nov 1994 08:12:31 GMT");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 200 OK\r\n");
+	
+	let mut request = make_request(~"/images/baz.jpg", ~"image/jpeg");
+	request.headers.insert(~"If-Modified-Since", ~"Tue, 15 Nov 1994 08:12:31 GMT");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 304 Not Modified\r\n");
+	
+	let mut request = make_request(~"/images/baz.jpg", ~"image/jpeg");
+	request.headers.insert(~"If-Modified-Since", ~"Mon, 14 Nov 1994 08:12:31 GMT");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 200 OK\r\n");
+}
+
+#[test]
+fn compressed_conditional_get()
+{
+	let config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		load_rsrc: null_loader,
+		valid_rsrc: |path| {path.to_str().ends_with(".txt")},
+		rsrc_info: |_path| {option::Some(RsrcMetadata {size: 100u, modified: 784887151i64})},
+		compress_types: ~[~"text/*"]
+		, .. initialize_config()};
+		
+	let mut tasks = LinearMap();
+	let sse_port = oldcomm::Port();
+	let sse_chan = oldcomm::Chan(&sse_port);
+	
+	let request = make_request(~"/notes.txt", ~"text/plain");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 200 OK\r\n");
+	assert header.contains("Content-Encoding: gzip\r\n");
+	assert header.contains("Vary: Accept-Encoding\r\n");
+	assert header.contains("ETag: \"64-2ec86d6f-gzip\"\r\n");
+	
+	let mut request = make_request(~"/notes.txt", ~"text/plain");
+	request.headers.remove("Accept-Encoding");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 200 OK\r\n");
+	assert !header.contains("Content-Encoding:");
+	assert header.contains("ETag: \"64-2ec86d6f\"\r\n");
+	
+	// The gzipped copy is still valid for clients which accept gzip.
+	let mut request = make_request(~"/notes.txt", ~"text/plain");
+	request.headers.insert(~"If-None-Match", ~"\"64-2ec86d6f-gzip\"");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 304 Not Modified\r\n");
+	assert header.contains("ETag: \"64-2ec86d6f-gzip\"\r\n");
+	
+	// But not for clients which don't.
+	let mut request = make_request(~"/notes.txt", ~"text/plain");
+	request.headers.remove("Accept-Encoding");
+	request.headers.insert(~"If-None-Match", ~"\"64-2ec86d6f-gzip\"");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 200 OK\r\n");
+	assert header.contains("ETag: \"64-2ec86d6f\"\r\n");
+	
+	// And the identity copy is still valid for clients which accept gzip.
+	let mut request = make_request(~"/notes.txt", ~"text/plain");
+	request.headers.insert(~"If-None-Match", ~"\"64-2ec86d6f\"");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 304 Not Modified\r\n");
+	assert header.contains("ETag: \"64-2ec86d6f\"\r\n");
+}
+
+#[test]
+fn range_request()
+{
+	let config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		load_rsrc: null_loader,
+		valid_rsrc: |path| {path.to_str().ends_with(".mp4")},
+		rsrc_info: |_path| {option::Some(RsrcMetadata {size: 34u, modified: 784887151i64})}
+		, .. initialize_config()};
+		
+	let mut tasks = LinearMap();
+	let sse_port = oldcomm::Port();
+	let sse_chan = oldcomm::Chan(&sse_port);
+	
+	// The file is "server/html/movie.mp4 contents".
+	let request = make_request(~"/movie.mp4", ~"*/*");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 200 OK\r\n");
+	assert header.contains("Accept-Ranges: bytes\r\n");
+	assert header.contains("Content-Length: 30\r\n");
+	
+	let mut request = make_request(~"/movie.mp4", ~"*/*");
+	request.headers.insert(~"Range", ~"bytes=12-16");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 206 Partial Content\r\n");
+	assert header.contains("Content-Range: bytes 12-16/30\r\n");
+	assert header.contains("Content-Length: 5\r\n");
+	match body
+	{
+		BinaryBody(binary) => assert utils::check_strs(str::from_bytes(*binary), ~"movie"),
+		_ => fail fmt!("Expected binary body but found %?", body),
+	}
+	
+	let mut request = make_request(~"/movie.mp4", ~"*/*");
+	request.headers.insert(~"Range", ~"bytes=0-5,-8");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 206 Partial Content\r\n");
+	assert header.contains("Content-Type: multipart/byteranges; boundary=");
+	let body = body.to_str();
+	assert body.contains("Content-Type: video/mp4");
+	assert body.contains("Content-Range: bytes 0-5/30\r\n\r\n<binary data>\r\n--");
+	assert body.contains("Content-Range: bytes 22-29/30\r\n\r\n<binary data>\r\n--");
+	
+	// Overlapping ranges are merged.
+	let mut request = make_request(~"/movie.mp4", ~"*/*");
+	request.headers.insert(~"Range", ~"bytes=14-16,12-14");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 206 Partial Content\r\n");
+	assert header.contains("Content-Range: bytes 12-16/30\r\n");
+	
+	let mut request = make_request(~"/movie.mp4", ~"*/*");
+	request.headers.insert(~"Range", ~"bytes=100-");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 416 Requested Range Not Satisfiable\r\n");
+	assert header.contains("Content-Range: bytes */30\r\n");
+	
+	// The file has changed so the whole thing is returned.
+	let mut request = make_request(~"/movie.mp4", ~"*/*");
+	request.headers.insert(~"Range", ~"bytes=12-16");
+	request.headers.insert(~"If-Range", ~"\"22-2ec86d6e\"");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 200 OK\r\n");
+	
+	let mut request = make_request(~"/movie.mp4", ~"*/*");
+	request.headers.insert(~"Range", ~"bytes=12-16");
+	request.headers.insert(~"If-Range", ~"\"22-2ec86d6f\"");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 206 Partial Content\r\n");
+}
+
+#[test]
+fn streamed_file()
+{
+	let config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		load_rsrc: |path| {if path.to_str().ends_with(".mp4") {err_loader(path)} else {null_loader(path)}},
+		read_rsrc: |path| {if path.to_str() == ~"server/html/movie.mp4" {result::Ok(io::stdin())} else {result::Err(~"permission denied")}},
+		valid_rsrc: |path| {path.to_str().ends_with(".mp4")},
+		rsrc_info: |_path| {option::Some(RsrcMetadata {size: 5000u, modified: 784887151i64})},
+		stream_size: 4096u
+		, .. initialize_config()};
+		
+	let mut tasks = LinearMap();
+	let sse_port = oldcomm::Port();
+	let sse_chan = oldcomm::Chan(&sse_port);
+	
+	// The length comes from rsrc_info and load_rsrc isn't called.
+	let request = make_request(~"/movie.mp4", ~"*/*");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 200 OK\r\n");
+	assert header.contains("Content-Length: 5000\r\n");
+	assert utils::check_strs(body.to_str(), ~"<5000 bytes at 0>");
+	
+	// Files which can't be opened are reported before the header is sent.
+	let request = make_request(~"/trailer.mp4", ~"*/*");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 404 Not Found\r\n");
+	assert str::contains(body.to_str(), "server/html/not-found.html contents");
+	
+	let mut request = make_request(~"/movie.mp4", ~"*/*");
+	request.headers.insert(~"Range", ~"bytes=-100");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 206 Partial Content\r\n");
+	assert header.contains("Content-Range: bytes 4900-4999/5000\r\n");
+	assert header.contains("Content-Length: 100\r\n");
+	assert utils::check_strs(body.to_str(), ~"<100 bytes at 4900>");
+}
+
+#[test]
+fn produced_body()
+{
+	fn csv_view(_config: &Config, _request: &Request, response: Response) -> Response
+	{
+		let row = @mut 0u;
+		let producer: BodyProducer = ||
+		{
+			*row += 1u;
+			result::Ok(if *row <= 2u {option::Some(str::to_bytes(fmt!("%?,row %?\n", *row, *row)))} else {option::None})
+		};
+		Response {body: ProducerBody(producer), ..response}
+	}
+	
+	fn report_view(_config: &Config, _request: &Request, response: Response) -> Response
+	{
+		let mut response = response;
+		response.headers.insert(~"Content-Length", ~"100");
+		
+		let producer: BodyProducer = || {result::Ok(option::None)};
+		Response {body: CompoundBody(@[@StringBody(@~"header\n"), @ProducerBody(producer)]), ..response}
+	}
+	
+	fn signed_view(_config: &Config, _request: &Request, response: Response) -> Response
+	{
+		let trailers: TrailerProducer = || {headers_from_vector(~[(~"Content-MD5", ~"ZjY5ODU3ZmVkNjE2NDk2MQ==")])};
+		Response {body: TrailedBody(@StringBody(@~"1,row 1\n"), @~[~"Content-MD5"], trailers), ..response}
+	}
+	
+	let config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		routes: ~[TypedRoute(~"export", ~"GET", ~"/export", ~"text/csv"), TypedRoute(~"report", ~"GET", ~"/report", ~"text/csv"), TypedRoute(~"signed", ~"GET", ~"/signed", ~"text/csv")],
+		views: utils::linear_map_from_vector(~[(~"export",  csv_view), (~"report",  report_view), (~"signed",  signed_view)]),
+		load_rsrc: null_loader,
+		compress_types: ~[~"text/*"]
+		, .. initialize_config()};
+		
+	let mut tasks = LinearMap();
+	let sse_port = oldcomm::Port();
+	let sse_chan = oldcomm::Chan(&sse_port);
+	
+	let request = make_request(~"/export", ~"text/csv");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 200 OK\r\n");
+	assert header.contains("Transfer-Encoding: chunked\r\n");
+	assert !header.contains("Content-Length");
+	assert !header.contains("Content-Encoding");
+	match body
+	{
+		ProducerBody(producer) =>
+		{
+			assert producer() == result::Ok(option::Some(str::to_bytes("1,row 1\n")));
+			assert producer() == result::Ok(option::Some(str::to_bytes("2,row 2\n")));
+			assert producer() == result::Ok(option::None);
+		}
+		_ => fail fmt!("Expected producer body but found %?", body),
+	}
+	
+	// Nested producers are chunked too and views can't give them a length.
+	let request = make_request(~"/report", ~"text/csv");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 200 OK\r\n");
+	assert header.contains("Transfer-Encoding: chunked\r\n");
+	assert !header.contains("Content-Length");
+	
+	// Bodies with trailers are chunked and the trailers are declared.
+	let request = make_request(~"/signed", ~"text/csv");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 200 OK\r\n");
+	assert header.contains("Transfer-Encoding: chunked\r\n");
+	assert header.contains("Trailer: Content-MD5\r\n");
+	assert !header.contains("Content-Length");
+	assert utils::check_strs(body.to_str(), ~"1,row 1\n");
+	
+	// HTTP/1.0 clients don't understand chunked so the connection is closed instead.
+	let request = HttpRequest {minor_version: 0, ..make_request(~"/export", ~"text/csv")};
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 200 OK\r\n");
+	assert header.contains("Connection: close\r\n");
+	assert !header.contains("Transfer-Encoding");
+	assert !header.contains("Content-Length");
+	
+	// Channel bodies are abandoned if their task stops sending chunks.
+	let port = oldcomm::Port();
+	let chan = oldcomm::Chan(&port);
+	oldcomm::send(chan, str::to_bytes(~"1,row 1\n"));
+	match channel_body(port, 10u)
+	{
+		ProducerBody(producer) =>
+		{
+			assert producer() == result::Ok(option::Some(str::to_bytes("1,row 1\n")));
+			assert producer().is_err();
+		}
+		body => fail fmt!("Expected producer body but found %?", body),
+	}
+	
+	assert utils::check_strs(make_chunk(StringBody(@~"hello world")).to_str(), ~"B\r\nhello world\r\n");
+}
+
+#[test]
+fn directory_index()
+{
+	fn listing_loader(path: &Path) -> result::Result<~[u8], ~str>
+	{
+		if path.to_str().ends_with("autoindex.html")
+		{
+			result::Ok(str::to_bytes("{{#entries}}{{url}} {{size}};{{/entries}}"))
+		}
+		else
+		{
+			null_loader(path)
+		}
+	}
+	
+	fn list_dir(path: &Path) -> option::Option<~[~str]>
+	{
+		let path = path.to_str();
+		if path == ~"server/html/docs"
+		{
+			option::Some(~[~"index.html", ~"intro.txt"])
+		}
+		else if path == ~"server/html/pics"
+		{
+			option::Some(~[~"b.png", ~".hidden.png", ~"a.png", ~"old", ~"my photo#1.png"])
+		}
+		else if path == ~"server/html/pics/old"
+		{
+			option::Some(~[])
+		}
+		else
+		{
+			option::None
+		}
+	}
+	
+	let mut config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		load_rsrc: listing_loader,
+		valid_rsrc: |path| {path.to_str() == ~"server/html/docs/index.html" || path.to_str().ends_with(".png")},
+		rsrc_info: |_path| {option::Some(RsrcMetadata {size: 100u, modified: 784887151i64})},
+		list_rsrc: list_dir
+		, .. initialize_config()};
+		
+	let mut tasks = LinearMap();
+	let sse_port = oldcomm::Port();
+	let sse_chan = oldcomm::Chan(&sse_port);
+	
+	let request = make_request(~"/docs", ~"text/html");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 301 Moved Permanently\r\n");
+	assert header.contains("Location: /docs/\r\n");
+	
+	let request = make_request(~"/docs?b=2&a=x y", ~"text/html");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 301 Moved Permanently\r\n");
+	assert header.contains("Location: /docs/?a=x%20y&b=2\r\n");
+	
+	let request = make_request(~"/docs/", ~"text/html");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 200 OK\r\n");
+	assert utils::check_strs(body.to_str(), ~"server/html/docs/index.html contents");
+	
+	let request = make_request(~"/pics/", ~"text/html");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 404 Not Found\r\n");
+	
+	config.autoindex = ~"autoindex.html";
+	let request = make_request(~"/pics/", ~"text/html");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 200 OK\r\n");
+	assert utils::check_strs(body.to_str(), ~"a.png 100;b.png 100;my%20photo%231.png 100;old/ -;");
+}
+
+#[test]
+fn mounted_files()
+{
+	let assets = Mount {
+		static_types: utils::linear_map_from_vector(~[(~".css", ~"text/css"), (~".dat", ~"application/octet-stream")]),
+		is_template: |_config, _path| {false},
+		cache_control: ~"max-age=86400",
+		.. Mount(~"/static", GenericPath::from_str(~"assets"))};
+		
+	let config = Config {
+		hosts: ~[~"localhost"],
+		server_info: ~"unit test",
+		resources_root: GenericPath::from_str(~"server/html"),
+		mounts: ~[assets],
+		load_rsrc: null_loader,
+		valid_rsrc: |path| {path.to_str().starts_with("assets/") || path.to_str() == ~"server/html/app.css"},
+		rsrc_info: |_path| {option::Some(RsrcMetadata {size: 100u, modified: 784887151i64})}
+		, .. initialize_config()};
+		
+	let mut tasks = LinearMap();
+	let sse_port = oldcomm::Port();
+	let sse_chan = oldcomm::Chan(&sse_port);
+	
+	let request = make_request(~"/static/app.css", ~"*/*");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 200 OK\r\n");
+	assert header.contains("Content-Type: text/css");
+	assert header.contains("Cache-Control: max-age=86400\r\n");
+	match body
+	{
+		BinaryBody(binary) => assert utils::check_strs(str::from_bytes(*binary), ~"assets/app.css contents"),
+		_ => fail fmt!("Expected binary body but found %?", body),
+	}
+	
+	let request = make_request(~"/static/data.dat", ~"*/*");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.contains("Content-Type: application/octet-stream");
+	
+	// Files which aren't beneath a mount come from resources_root.
+	let request = make_request(~"/app.css", ~"*/*");
+	let (header, body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 200 OK\r\n");
+	assert !header.contains("max-age");
+	assert utils::check_strs(body.to_str(), ~"server/html/app.css contents");
+	
+	let request = make_request(~"/staticx/data.dat", ~"*/*");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 404 Not Found\r\n");
+	
+	let request = make_request(~"/static/../server/html/app.css", ~"*/*");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 403 Forbidden\r\n");
+	
+	let request = make_request(~"/static/../assets-private/key.dat", ~"*/*");
+	let (header, _body) = process_request(&config, &mut tasks, sse_chan, request, ~"10.11.12.13", ~"1.2.3.4");
+	assert header.starts_with("HTTP/1.1 403 Forbidden\r\n");
+}
@@ -2,15 +2,67 @@
 //use socket::*;
 use connection::{handle_connection};
 
+/// Handle to a running server. Returned by start_server.
+pub struct Server
+{
+	priv registry: oldcomm::Chan<RegistryMesg>,
+	priv done: oldcomm::Port<uint>,
+	priv mut count: uint,			// number of listeners which haven't finished
+	priv mut stopped: bool,			// true once shutdown has been called
+}
+
+impl Server
+{
+	/// Blocks until the server stops accepting connections on all of its interfaces.
+	fn wait(&self)
+	{
+		while self.count > 0u
+		{
+			let result = oldcomm::recv(self.done);
+			self.count -= result;
+		}
+	}
+
+	/// Stops accepting new connections, lets requests which are being processed finish,
+	/// sends CloseEvent to every sse task, and returns once all connections have closed.
+	///
+	/// Note that this should not be called from within a view (the view's connection
+	/// can't close until the view returns). Calls after the first return immediately.
+	fn shutdown(&self)
+	{
+		if self.stopped
+		{
+			return;
+		}
+		self.stopped = true;
+		
+		info!("shutting down server");
+		let port = oldcomm::Port();
+		let chan = oldcomm::Chan(&port);
+		oldcomm::send(self.registry, Shutdown(chan));
+		oldcomm::recv(port);
+
+		self.wait();
+	}
+}
+
 /// Startup the server.
-/// 
-/// Currently this will run until a client does a GET on '/shutdown' in which case exit is called.
+///
+/// This will run until the server is shutdown (e.g. by a client doing a GET on '/shutdown' in the sample server).
 pub fn start(config: &Config)
+{
+	let server = start_server(config);
+	server.wait();
+}
+
+/// Like start except that this returns immediately. The returned handle can be used to
+/// shutdown the server.
+pub fn start_server(config: &Config) -> Server
 {
 	let port = oldcomm::Port::<uint>();
 	let chan = oldcomm::Chan::<uint>(&port);
-	let mut count = vec::len(config.hosts);
-	
+	let registry = spawn_registry(vec::len(config.hosts));
+
 	// Accept connections from clients on one or more interfaces.
 	for vec::each(config.hosts)
 	|hostA|
@@ -20,43 +72,217 @@ pub fn start(config: &Config)
 		do task::spawn_sched(task::SingleThreaded)
 		|move host|
 		{
-			let r = do result::chain(socket::socket::bind_socket(host, config2.port))
-			|shandle|
-			{
-				do result::chain(socket::socket::listen(shandle, 10i32))		// this will block the thread so we use task::ManualThreads to avoid blocking other tasks using that thread
-					|shandle| {attach(copy config2, copy host, shandle)}
-			};
-			if result::is_err(&r)
+			// The listening socket is closed when r goes out of scope so we need to do
+			// that before we tell wait that we're done.
 			{
-				error!("Couldn't start web server at %s: %s", host, result::get_err(&r));
+				let r = do result::chain(socket::socket::bind_socket(host, config2.port))
+				|shandle|
+				{
+					do result::chain(socket::socket::listen(shandle, 10i32))		// this will block the thread so we use task::ManualThreads to avoid blocking other tasks using that thread
+						|shandle| {attach(copy config2, copy host, shandle, registry)}
+				};
+				if result::is_err(&r)
+				{
+					error!("Couldn't start web server at %s: %s", host, result::get_err(&r));
+					oldcomm::send(registry, ListenerFailed);
+				}
 			}
 			oldcomm::send(chan, 1u);
 		};
 	};
-	
-	// Exit if we're not accepting on any interfaces (this is an unusual case
-	// likely only to happen in the event of errors).
-	while count > 0u
-	{
-		let result = oldcomm::recv(port);
-		count -= result;
-	}
+
+	// Note that if we're not accepting on any interfaces (this is an unusual case
+	// likely only to happen in the event of errors) wait will return immediately.
+	Server {registry: registry, done: port, count: vec::len(config.hosts), stopped: false}
 }
 
-priv fn attach(config: Config, host: ~str, shandle: @socket::socket::socket_handle) -> Result<@socket::socket::socket_handle, ~str>
+priv fn attach(config: Config, host: ~str, shandle: @socket::socket::socket_handle, registry: oldcomm::Chan<RegistryMesg>) -> Result<@socket::socket::socket_handle, ~str>
 {
 	info!("server is listening for new connections on %s:%?", host, config.port);
-	let config2 = copy config;
-	let host2 = copy host;
-	do result::chain(socket::socket::accept(shandle)) |result|
+
+	let stop_port = oldcomm::Port();
+	let stop_chan = oldcomm::Chan(&stop_port);
+	oldcomm::send(registry, AddListener(shandle.sockfd, stop_chan));
+
+	loop
 	{
-		info!("connected to client at %s", result.remote_addr);
+		// Shutting down the socket doesn't wake up accept on all platforms so we
+		// poll for connections and periodically check to see if we've been stopped.
+		if stop_port.peek()
+		{
+			break;
+		}
+		match posix::wait_for(shandle.sockfd, posix::POLLIN, 250u)
+		{
+			result::Ok(true) =>
+			{
+			}
+			result::Ok(false) =>
+			{
+				loop;
+			}
+			result::Err(ref mesg) =>
+			{
+				error!("failed waiting for connections on %s:%?: %s", host, config.port, *mesg);
+				break;
+			}
+		}
 		
-		// We're called by a SingleThread which blocks so we need our own thread to avoid starvation.
-		// We'll go ahead and start two threads so routes can benefit from some parallelism.
-		do task::spawn_sched(task::ManualThreads(2)) |copy config2, copy host2| {handle_connection(&config2, result.fd, host2, result.remote_addr)};
-		result::Ok(shandle)
-	};
-	attach(config, host, shandle)
+		let result = socket::socket::accept(shandle);
+		if stop_port.peek()
+		{
+			break;
+		}
+
+		match result
+		{
+			result::Ok(ref client) =>
+			{
+				info!("connected to client at %s", client.remote_addr);
+				let fd = client.fd;
+				let remote_addr = copy client.remote_addr;
+				oldcomm::send(registry, AddConnection(fd));
+
+				// We're called by a SingleThread which blocks so we need our own thread to avoid starvation.
+				// We'll go ahead and start two threads so routes can benefit from some parallelism.
+				let config2 = copy config;
+				let host2 = copy host;
+				do task::spawn_sched(task::ManualThreads(2)) |move config2, move host2, move remote_addr|
+				{
+					handle_connection(&config2, fd, host2, remote_addr);
+					oldcomm::send(registry, RemoveConnection(fd));
+				};
+			}
+			result::Err(ref mesg) =>
+			{
+				error!("accept on %s failed: %s", host, *mesg);
+			}
+		}
+	}
+
+	info!("server stopped listening for new connections on %s:%?", host, config.port);
+	result::Ok(shandle)
 }
 
+// ---- Registry --------------------------------------------------------------
+// The registry is a task which tracks the sockets used by the server so that
+// shutdown can stop the listeners and wait for the connections to finish.
+priv enum RegistryMesg
+{
+	AddListener(libc::c_int, oldcomm::Chan<()>),
+	ListenerFailed,						// sent instead of AddListener if the socket couldn't be bound
+	AddConnection(libc::c_int),
+	RemoveConnection(libc::c_int),
+	Shutdown(oldcomm::Chan<()>),		// replies once all connections have closed
+}
+
+priv fn spawn_registry(num_listeners: uint) -> oldcomm::Chan<RegistryMesg>
+{
+	let setup_po = oldcomm::Port();
+	let setup_ch = oldcomm::Chan(&setup_po);
+	do task::spawn_sched(task::SingleThreaded)
+	{
+		let po = oldcomm::Port();
+		let ch = oldcomm::Chan(&po);
+		oldcomm::send(setup_ch, ch);
+		manage_registry(po, num_listeners);
+	}
+	oldcomm::recv(setup_po)
+}
+
+// Shutdown may be called before all of the listeners have started so the registry
+// doesn't reply until every listener has registered (or failed to start).
+priv fn manage_registry(port: oldcomm::Port<RegistryMesg>, num_listeners: uint)
+{
+	let mut pending = num_listeners;
+	let mut listeners = ~[];
+	let mut connections = ~[];
+	let mut stopping = option::None;
+
+	loop
+	{
+		match oldcomm::recv(port)
+		{
+			AddListener(fd, chan) =>
+			{
+				pending -= 1u;
+				if stopping.is_some()
+				{
+					stop_listener(fd, chan);
+				}
+				else
+				{
+					vec::push(&mut listeners, (fd, chan));
+				}
+			}
+			ListenerFailed =>
+			{
+				pending -= 1u;
+			}
+			AddConnection(fd) =>
+			{
+				if stopping.is_some()
+				{
+					// Connection was accepted just before we shut the listener down.
+					posix::shutdown(fd, posix::SHUT_RD);
+				}
+				vec::push(&mut connections, fd);
+			}
+			RemoveConnection(fd) =>
+			{
+				connections = vec::filter(connections, |c| {*c != fd});
+			}
+			Shutdown(chan) =>
+			{
+				// Stop accepting new connections.
+				for listeners.each |&(fd, stop)|
+				{
+					stop_listener(fd, stop);
+				}
+
+				// Stop reading new requests. Requests which have already been read
+				// will be processed and then handle_connection will close the sse
+				// tasks and return.
+				for connections.each |fd|
+				{
+					posix::shutdown(*fd, posix::SHUT_RD);
+				}
+				stopping = option::Some(chan);
+			}
+		}
+
+		if stopping.is_some() && pending == 0u && vec::is_empty(connections)
+		{
+			oldcomm::send(option::get(stopping), ());
+			break;
+		}
+	}
+}
+
+// The listener closes its socket once it notices that it has been stopped.
+priv fn stop_listener(fd: libc::c_int, stop: oldcomm::Chan<()>)
+{
+	oldcomm::send(stop, ());
+	posix::shutdown(fd, posix::SHUT_RDWR);
+}
+
+// ---- Unit Tests ------------------------------------------------------------
+#[test]
+fn start_and_shutdown()
+{
+	let config = Config {hosts: ~[~"127.0.0.1"], port: 8093_u16, .. initialize_config()};
+	
+	// This will typically shutdown before the listener has registered itself.
+	let server = start_server(&config);
+	server.shutdown();
+	
+	// Once shutdown returns the listening socket has been closed.
+	assert socket::socket::bind_socket(~"127.0.0.1", 8093_u16).is_ok();
+	
+	let server = start_server(&config);
+	server.shutdown();
+	
+	// The registry has exited by now so these can't block.
+	server.wait();
+	server.shutdown();
+}
@@ -13,6 +13,8 @@ pub type OpenSse = fn~ (config: &Config, request: &Request, channel: PushChan) -
 ///
 /// In the simplest case the data would contain a single line with the format: 
 /// "data: arbitrary text\n". For more details see [event stream](http://dev.w3.org/html5/eventsource/#event-stream-interpretation).
+/// Sending an empty string finishes the event stream (the task should exit after doing this).
+/// Tasks started with spawn_sse do this automatically when they exit.
 pub type PushChan = oldcomm::Chan<~str>;
 
 /// The port sse tasks use to respond to events from the server.
@@ -37,8 +39,7 @@ pub enum ControlEvent
 // This is invoked when the client sends a GET on behalf of an event source.
 pub fn process_sse(config: &Config, tasks: &mut LinearMap<~str, ControlChan>, push_data: PushChan, request: &Request) -> (Response, Body)
 {
-	let mut code = ~"200";
-	let mut mesg = ~"OK";
+	let mut status = status::OK;
 	let mut mime = ~"text/event-stream; charset=utf-8";
 	
 	match tasks.find(&request.path)
@@ -51,14 +52,13 @@ pub fn process_sse(config: &Config, tasks: &mut LinearMap<~str, ControlChan>, pu
 		{
 			if !openSse(config, tasks, request, push_data)
 			{
-				code = ~"404";
-				mesg = ~"Not Found";
+				status = status::NotFound;
 				mime = ~"text/event-stream";
 			}
 		}
 	}
 	
-	let mut response = request::make_initial_response(config, code, mesg, mime, request);
+	let mut response = request::make_initial_response(config, status, mime, request);
 	response.headers.insert(~"Transfer-Encoding", ~"chunked");
 	response.headers.insert(~"Cache-Control", ~"no-cache");
 	(response, StringBody(@~"\n\n"))
@@ -84,6 +84,30 @@ pub fn openSse(config: &Config, tasks: &mut LinearMap<~str, ControlChan>, reques
 	}
 }
 
+/// Spawns a task for an sse session and returns the channel used to communicate with it.
+/// f is called with the port for the server's ControlEvents. Once f returns (e.g. after a
+/// CloseEvent) or fails an empty string is sent to push so that the event stream is finished.
+pub fn spawn_sse(push: PushChan, f: fn~ (control: ControlPort)) -> ControlChan
+{
+	let setup_po = oldcomm::Port();
+	let setup_ch = oldcomm::Chan(&setup_po);
+	do task::spawn_sched(task::SingleThreaded) |move f|
+	{
+		let result = do task::try |move f|
+		{
+			let po = oldcomm::Port();
+			oldcomm::send(setup_ch, oldcomm::Chan(&po));
+			f(po);
+		};
+		if result.is_err()
+		{
+			error!("sse task failed");
+		}
+		oldcomm::send(push, ~"");
+	}
+	oldcomm::recv(setup_po)
+}
+
 pub fn close_sses(tasks: &LinearMap<~str, ControlChan>)
 {
 	info!("closing all sse");
@@ -95,7 +119,7 @@ pub fn close_sses(tasks: &LinearMap<~str, ControlChan>)
 
 pub fn make_response(config: &Config) -> Response
 {
-	let headers = utils::linear_map_from_vector(~[
+	let headers = headers_from_vector(~[
 		(~"Cache-Control", ~"no-cache"),
 		(~"Content-Type", ~"text/event-stream; charset=utf-8"),
 		(~"Date", std::time::now_utc().rfc822()),
@@ -103,6 +127,35 @@ pub fn make_response(config: &Config) -> Response
 		(~"Transfer-Encoding", ~"chunked"),
 	]);
 	
-	Response {status: ~"200 OK", headers: headers, body: StringBody(@~""), template: ~"", context: std::map::HashMap()}
+	Response {status: status::OK, headers: headers, body: StringBody(@~""), template: ~"", context: std::map::HashMap()}
 }
 
+// ---- Unit Tests ------------------------------------------------------------
+#[test]
+fn finished_sse_tasks()
+{
+	fn spawn_test_sse(push: PushChan) -> ControlChan
+	{
+		do spawn_sse(push) |control_port|
+		{
+			match oldcomm::recv(control_port)
+			{
+				CloseEvent => {}
+				RefreshEvent => fail ~"refresh isn't supported",
+			}
+		}
+	}
+	
+	let push_po = oldcomm::Port();
+	let push = oldcomm::Chan(&push_po);
+	
+	// The stream is finished when the task returns.
+	let control = spawn_test_sse(push);
+	oldcomm::send(control, CloseEvent);
+	assert oldcomm::recv(push_po) == ~"";
+	
+	// And when it fails.
+	let control = spawn_test_sse(push);
+	oldcomm::send(control, RefreshEvent);
+	assert oldcomm::recv(push_po) == ~"";
+}
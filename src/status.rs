@@ -0,0 +1,230 @@
+//! HTTP response status codes.
+// See section 10 of http://www.w3.org/Protocols/rfc2616/rfc2616.html
+
+/// The status line of a response (e.g. "404 Not Found").
+///
+/// This has a variant for each of the RFC 2616 status codes. CustomStatus may be
+/// used for other codes.
+#[deriving_eq]
+pub enum Status
+{
+	Continue,								// 100
+	SwitchingProtocols,						// 101
+
+	OK,										// 200
+	Created,								// 201
+	Accepted,								// 202
+	NonAuthoritativeInformation,			// 203
+	NoContent,								// 204
+	ResetContent,							// 205
+	PartialContent,							// 206
+
+	MultipleChoices,						// 300
+	MovedPermanently,						// 301
+	Found,									// 302
+	SeeOther,								// 303
+	NotModified,							// 304
+	UseProxy,								// 305
+	TemporaryRedirect,						// 307
+
+	BadRequest,								// 400
+	Unauthorized,							// 401
+	PaymentRequired,						// 402
+	Forbidden,								// 403
+	NotFound,								// 404
+	MethodNotAllowed,						// 405
+	NotAcceptable,							// 406
+	ProxyAuthenticationRequired,			// 407
+	RequestTimeout,							// 408
+	Conflict,								// 409
+	Gone,									// 410
+	LengthRequired,							// 411
+	PreconditionFailed,						// 412
+	RequestEntityTooLarge,					// 413
+	RequestURITooLong,						// 414
+	UnsupportedMediaType,					// 415
+	RequestedRangeNotSatisfiable,			// 416
+	ExpectationFailed,						// 417
+	RequestHeaderFieldsTooLarge,			// 431 (from RFC 6585)
+
+	InternalServerError,					// 500
+	NotImplemented,							// 501
+	BadGateway,								// 502
+	ServiceUnavailable,						// 503
+	GatewayTimeout,							// 504
+	HTTPVersionNotSupported,				// 505
+
+	CustomStatus(uint, ~str),				// code and reason phrase
+}
+
+impl Status
+{
+	/// Returns the numeric status code, e.g. 404.
+	pure fn code(&self) -> uint
+	{
+		match *self
+		{
+			Continue						=> 100u,
+			SwitchingProtocols				=> 101u,
+
+			OK								=> 200u,
+			Created							=> 201u,
+			Accepted						=> 202u,
+			NonAuthoritativeInformation		=> 203u,
+			NoContent						=> 204u,
+			ResetContent					=> 205u,
+			PartialContent					=> 206u,
+
+			MultipleChoices					=> 300u,
+			MovedPermanently				=> 301u,
+			Found							=> 302u,
+			SeeOther						=> 303u,
+			NotModified						=> 304u,
+			UseProxy						=> 305u,
+			TemporaryRedirect				=> 307u,
+
+			BadRequest						=> 400u,
+			Unauthorized					=> 401u,
+			PaymentRequired					=> 402u,
+			Forbidden						=> 403u,
+			NotFound						=> 404u,
+			MethodNotAllowed				=> 405u,
+			NotAcceptable					=> 406u,
+			ProxyAuthenticationRequired		=> 407u,
+			RequestTimeout					=> 408u,
+			Conflict						=> 409u,
+			Gone							=> 410u,
+			LengthRequired					=> 411u,
+			PreconditionFailed				=> 412u,
+			RequestEntityTooLarge			=> 413u,
+			RequestURITooLong				=> 414u,
+			UnsupportedMediaType			=> 415u,
+			RequestedRangeNotSatisfiable	=> 416u,
+			ExpectationFailed				=> 417u,
+			RequestHeaderFieldsTooLarge		=> 431u,
+
+			InternalServerError				=> 500u,
+			NotImplemented					=> 501u,
+			BadGateway						=> 502u,
+			ServiceUnavailable				=> 503u,
+			GatewayTimeout					=> 504u,
+			HTTPVersionNotSupported			=> 505u,
+
+			CustomStatus(code, _)			=> code,
+		}
+	}
+
+	/// Returns the reason phrase, e.g. "Not Found".
+	pure fn reason(&self) -> ~str
+	{
+		match *self
+		{
+			Continue						=> ~"Continue",
+			SwitchingProtocols				=> ~"Switching Protocols",
+
+			OK								=> ~"OK",
+			Created							=> ~"Created",
+			Accepted						=> ~"Accepted",
+			NonAuthoritativeInformation		=> ~"Non-Authoritative Information",
+			NoContent						=> ~"No Content",
+			ResetContent					=> ~"Reset Content",
+			PartialContent					=> ~"Partial Content",
+
+			MultipleChoices					=> ~"Multiple Choices",
+			MovedPermanently				=> ~"Moved Permanently",
+			Found							=> ~"Found",
+			SeeOther						=> ~"See Other",
+			NotModified						=> ~"Not Modified",
+			UseProxy						=> ~"Use Proxy",
+			TemporaryRedirect				=> ~"Temporary Redirect",
+
+			BadRequest						=> ~"Bad Request",
+			Unauthorized					=> ~"Unauthorized",
+			PaymentRequired					=> ~"Payment Required",
+			Forbidden						=> ~"Forbidden",
+			NotFound						=> ~"Not Found",
+			MethodNotAllowed				=> ~"Method Not Allowed",
+			NotAcceptable					=> ~"Not Acceptable",
+			ProxyAuthenticationRequired		=> ~"Proxy Authentication Required",
+			RequestTimeout					=> ~"Request Timeout",
+			Conflict						=> ~"Conflict",
+			Gone							=> ~"Gone",
+			LengthRequired					=> ~"Length Required",
+			PreconditionFailed				=> ~"Precondition Failed",
+			RequestEntityTooLarge			=> ~"Request Entity Too Large",
+			RequestURITooLong				=> ~"Request-URI Too Long",
+			UnsupportedMediaType			=> ~"Unsupported Media Type",
+			RequestedRangeNotSatisfiable	=> ~"Requested Range Not Satisfiable",
+			ExpectationFailed				=> ~"Expectation Failed",
+			RequestHeaderFieldsTooLarge		=> ~"Request Header Fields Too Large",
+
+			InternalServerError				=> ~"Internal Server Error",
+			NotImplemented					=> ~"Not Implemented",
+			BadGateway						=> ~"Bad Gateway",
+			ServiceUnavailable				=> ~"Service Unavailable",
+			GatewayTimeout					=> ~"Gateway Timeout",
+			HTTPVersionNotSupported			=> ~"HTTP Version Not Supported",
+
+			CustomStatus(_, ref reason)		=> copy *reason,
+		}
+	}
+}
+
+pub impl Status : ToStr
+{
+	pure fn to_str() -> ~str
+	{
+		fmt!("%? %s", self.code(), self.reason())
+	}
+}
+
+/// Returns the Status for a numeric code. Codes not defined by RFC 2616
+/// (or RFC 6585) will be a CustomStatus with reason.
+pub fn from_code(code: uint, reason: &str) -> Status
+{
+	for vec::each(known_statuses()) |status|
+	{
+		if status.code() == code
+		{
+			return copy *status;
+		}
+	}
+	CustomStatus(code, reason.to_owned())
+}
+
+priv fn known_statuses() -> ~[Status]
+{
+	~[
+		Continue, SwitchingProtocols,
+		OK, Created, Accepted, NonAuthoritativeInformation, NoContent, ResetContent, PartialContent,
+		MultipleChoices, MovedPermanently, Found, SeeOther, NotModified, UseProxy, TemporaryRedirect,
+		BadRequest, Unauthorized, PaymentRequired, Forbidden, NotFound, MethodNotAllowed, NotAcceptable,
+		ProxyAuthenticationRequired, RequestTimeout, Conflict, Gone, LengthRequired, PreconditionFailed,
+		RequestEntityTooLarge, RequestURITooLong, UnsupportedMediaType, RequestedRangeNotSatisfiable,
+		ExpectationFailed, RequestHeaderFieldsTooLarge,
+		InternalServerError, NotImplemented, BadGateway, ServiceUnavailable, GatewayTimeout, HTTPVersionNotSupported,
+	]
+}
+
+// ---- Unit Tests ------------------------------------------------------------
+#[test]
+fn status_strs()
+{
+	assert OK.to_str() == ~"200 OK";
+	assert NotFound.to_str() == ~"404 Not Found";
+	assert HTTPVersionNotSupported.to_str() == ~"505 HTTP Version Not Supported";
+	assert CustomStatus(299, ~"Whatever").to_str() == ~"299 Whatever";
+}
+
+#[test]
+fn status_from_code()
+{
+	assert from_code(404u, ~"ignored") == NotFound;
+	assert from_code(431u, ~"ignored") == RequestHeaderFieldsTooLarge;
+	assert from_code(299u, ~"Whatever") == CustomStatus(299u, ~"Whatever");
+
+	for vec::each(known_statuses()) |status|
+	{
+		assert from_code(status.code(), ~"") == *status;
+	}
+}
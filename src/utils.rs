@@ -107,6 +107,26 @@ pub fn truncate_str(s: &str, max_chars: uint) -> ~str
 	}
 }
 
+// Percent-encodes the bytes in a path which aren't unreserved characters or slashes
+// (see section 2.3 of RFC 3986).
+pub fn encode_url_path(path: &str) -> ~str
+{
+	let mut result = ~"";
+	for str::each(path) |b|
+	{
+		if (b >= 'a' as u8 && b <= 'z' as u8) || (b >= 'A' as u8 && b <= 'Z' as u8) || (b >= '0' as u8 && b <= '9' as u8) || str::contains_char("-_.~/", b as char)
+		{
+			str::push_char(&mut result, b as char);
+		}
+		else
+		{
+			str::push_char(&mut result, '%');
+			result += fmt!("%X%X", (b >> 4) as uint, (b & 0x0Fu8) as uint);
+		}
+	}
+	result
+}
+
 #[cfg(test)]
 pub fn check_strs(actual: &str, expected: &str) -> bool
 {